@@ -6,17 +6,22 @@ use pon::*;
 use std::fs::File;
 use std::io::BufReader;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::collections::hash_map::Keys;
 use std::collections::hash_map::Entry;
 use std::path::Path;
+use std::path::PathBuf;
 use std::io::Write;
+use std::io::Read;
 use std::cell::RefCell;
 use std::cell::Ref;
 use std::any::Any;
 use std::rc::Rc;
+use std::mem;
 
 use xml::reader::EventReader;
 use xml::reader::events::*;
+use xml::common::HasPosition;
 
 #[derive(PartialEq, Debug, Clone)]
 pub enum DocError {
@@ -24,7 +29,17 @@ pub enum DocError {
     NoSuchProperty(String),
     NoSuchEntity(EntityId),
     CantFindEntityByName(String),
-    InvalidParent
+    InvalidParent,
+    InvalidChildIndex(usize),
+    DependencyCycle(Vec<PropRef>),
+    // Properties that couldn't be resolved, with the reason for each
+    UnresolvedProperties(Vec<(PropRef, DocError)>),
+    LoadDiagnostic(LoadDiagnostic),
+    Io(PathBuf, String),
+    Xml(String),
+    Json(String),
+    ParseError(PonParseError),
+    Binary(String)
 }
 
 impl From<PonTranslateErr> for DocError {
@@ -33,6 +48,133 @@ impl From<PonTranslateErr> for DocError {
     }
 }
 
+#[derive(PartialEq, Debug, Clone)]
+pub enum LoadDiagnosticKind {
+    AppendEntityFailed,
+    PropertyParseError,
+    SetPropertyFailed,
+    UnresolvedProperty
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct LoadDiagnostic {
+    pub kind: LoadDiagnosticKind,
+    pub entity_type_name: String,
+    pub entity_name: Option<String>,
+    pub attribute: Option<String>,
+    // the attribute value that caused the problem, if any
+    pub text: String,
+    pub message: String,
+    // 0 when the source has no positions, like a snapshot
+    pub line: u64,
+    pub column: u64
+}
+
+impl ToString for LoadDiagnostic {
+    fn to_string(&self) -> String {
+        let entity = match &self.entity_name {
+            &Some(ref name) => format!("{} {:?}", self.entity_type_name, name),
+            &None => self.entity_type_name.to_string()
+        };
+        match &self.attribute {
+            &Some(ref attribute) => format!("{}:{}: {:?} in property {} of entity {}: {} with error: {}",
+                self.line, self.column, self.kind, attribute, entity, self.text, self.message),
+            &None => format!("{}:{}: {:?} for entity {}: {}", self.line, self.column, self.kind, entity, self.message)
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct DocumentLoadReport {
+    pub diagnostics: Vec<LoadDiagnostic>
+}
+
+impl DocumentLoadReport {
+    pub fn print_warnings(&self) {
+        if self.diagnostics.len() > 0 {
+            println!("{} WARNINGS PARSING DOCUMENT:", self.diagnostics.len());
+            println!("{}", self.to_string());
+        }
+    }
+    // In strict mode the first diagnostic fails the load
+    fn check_strict(&self, options: &DocumentLoadOptions) -> Result<(), DocError> {
+        match self.diagnostics.first() {
+            Some(diagnostic) if options.strict => Err(DocError::LoadDiagnostic(diagnostic.clone())),
+            _ => Ok(())
+        }
+    }
+}
+
+impl ToString for DocumentLoadReport {
+    fn to_string(&self) -> String {
+        let lines: Vec<String> = self.diagnostics.iter().map(|d| d.to_string()).collect();
+        lines.join("\n")
+    }
+}
+
+pub struct DocumentLoadOptions {
+    // fail the load with the first diagnostic instead of reporting it
+    pub strict: bool
+}
+impl DocumentLoadOptions {
+    pub fn default() -> DocumentLoadOptions {
+        DocumentLoadOptions {
+            strict: false
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum AttributeOrder {
+    // all attributes sorted by name, the name attribute included
+    Alphabetical,
+    // the name attribute first, then the properties sorted by name
+    NameFirst
+}
+
+pub struct DocumentWriteOptions {
+    // None writes the whole document on a single line
+    pub indent: Option<String>,
+    pub xml_version: xml::common::XmlVersion,
+    pub attribute_order: AttributeOrder,
+    // write the current values of dependency references instead of the @references
+    pub unwrap_dependencies: bool
+}
+impl DocumentWriteOptions {
+    pub fn default() -> DocumentWriteOptions {
+        DocumentWriteOptions {
+            indent: None,
+            xml_version: xml::common::XmlVersion::Version11,
+            attribute_order: AttributeOrder::Alphabetical,
+            unwrap_dependencies: false
+        }
+    }
+    pub fn pretty() -> DocumentWriteOptions {
+        DocumentWriteOptions {
+            indent: Some("  ".to_string()),
+            xml_version: xml::common::XmlVersion::Version10,
+            attribute_order: AttributeOrder::NameFirst,
+            unwrap_dependencies: false
+        }
+    }
+}
+
+// A plain copy of a document tree, independent of any Document, for converting to and from other
+// formats. Properties are sorted by key and kept as written, so dependency references stay
+// references rather than their current values.
+#[derive(PartialEq, Debug, Clone)]
+pub struct DocumentSnapshot {
+    pub root: Option<EntitySnapshot>
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct EntitySnapshot {
+    pub type_name: String,
+    pub name: Option<String>,
+    pub properties: Vec<(String, Pon)>,
+    pub children: Vec<EntitySnapshot>
+}
+
 pub type EntityId = u64;
 
 pub type EntityIter<'a> = Keys<'a, EntityId, Entity>;
@@ -42,6 +184,7 @@ pub type PropertyIter<'a> = Keys<'a, String, Property>;
 #[derive(Debug)]
 struct Property {
     expression: Rc<RefCell<Option<Pon>>>,
+    dependencies: Vec<PropRef>,
     dependants: Vec<PropRef>
 }
 
@@ -64,6 +207,7 @@ impl Entity {
             Entry::Vacant(v) => {
                 v.insert(Property {
                     expression: Rc::new(RefCell::new(None)),
+                    dependencies: vec![],
                     dependants: vec![]
                 })
             }
@@ -71,13 +215,24 @@ impl Entity {
     }
 }
 
+// A property whose expression couldn't be resolved yet, typically because it refers to an
+// entity by a name that hasn't been added yet. Retried whenever a named entity is added.
+#[derive(Debug)]
+struct PendingProperty {
+    prop_ref: PropRef,
+    expression: Pon,
+    error: DocError
+}
+
 pub struct Document {
     id_counter: EntityId,
     root: Option<EntityId>,
     entities: HashMap<EntityId, Entity>,
     entity_ids_by_name: HashMap<String, EntityId>,
+    pending_properties: Vec<PendingProperty>,
     pub resources: HashMap<String, Box<Any>>,
     pub on_entity_added: Option<Box<Fn(&EntityId) -> ()>>,
+    pub on_entity_removed: Option<Box<Fn(&EntityId) -> ()>>,
     pub on_property_set: Option<Box<Fn(&EntityId, &str) -> ()>>
 }
 
@@ -88,8 +243,10 @@ impl Document {
             root: None,
             entities: HashMap::new(),
             entity_ids_by_name: HashMap::new(),
+            pending_properties: vec![],
             resources: HashMap::new(),
             on_entity_added: None,
+            on_entity_removed: None,
             on_property_set: None
         }
     }
@@ -119,6 +276,7 @@ impl Document {
             }
             self.root = Some(id);
         }
+        let named = entity.name.is_some();
         if let &Some(ref name) = &entity.name {
             self.entity_ids_by_name.insert(name.clone(), entity.id);
         }
@@ -126,14 +284,236 @@ impl Document {
         if let &Some(ref cb) = &self.on_entity_added {
             cb(&id);
         }
+        if named {
+            self.bind_pending_properties();
+        }
         return Ok(id);
     }
+    pub fn remove_entity(&mut self, entity_id: &EntityId) -> Result<(), DocError> {
+        let parent_id = match self.entities.get(entity_id) {
+            Some(entity) => entity.parent_id,
+            None => return Err(DocError::NoSuchEntity(*entity_id))
+        };
+        match parent_id {
+            Some(parent_id) => {
+                if let Some(parent) = self.entities.get_mut(&parent_id) {
+                    parent.children_ids.retain(|id| id != entity_id);
+                }
+            },
+            None => self.root = None
+        }
+        let mut removed_ids = vec![];
+        self.collect_subtree(entity_id, &mut removed_ids);
+        let mut invalidated = vec![];
+        for id in &removed_ids {
+            let entity = self.entities.remove(id).unwrap();
+            if let Some(name) = entity.name {
+                if self.entity_ids_by_name.get(&name) == Some(id) {
+                    self.entity_ids_by_name.remove(&name);
+                }
+            }
+            for (_, prop) in entity.properties {
+                // Anyone still holding this value through a ResolvedDependency will get a
+                // ReferenceToNonExistentProperty from now on, rather than the old value
+                *prop.expression.borrow_mut() = None;
+                for dependant in prop.dependants {
+                    if !removed_ids.contains(&dependant.entity_id) && !invalidated.contains(&dependant) {
+                        invalidated.push(dependant);
+                    }
+                }
+            }
+        }
+        for (_, entity) in self.entities.iter_mut() {
+            for (_, prop) in entity.properties.iter_mut() {
+                prop.dependants.retain(|prop_ref| !removed_ids.contains(&prop_ref.entity_id));
+            }
+        }
+        self.pending_properties.retain(|pending| !removed_ids.contains(&pending.prop_ref.entity_id));
+        if let &Some(ref cb) = &self.on_entity_removed {
+            for id in &removed_ids {
+                cb(id);
+            }
+        }
+        for prop_ref in invalidated {
+            self.unbind_removed_dependencies(&prop_ref);
+        }
+        Ok(())
+    }
+    // For a property that depended on removed entities. It's set again in case its references now
+    // resolve to something else, otherwise it's deferred until they resolve again.
+    fn unbind_removed_dependencies(&mut self, prop_ref: &PropRef) {
+        let expression = match self.entities.get(&prop_ref.entity_id).and_then(|entity| entity.properties.get(&prop_ref.property_key)) {
+            Some(prop) => match &*prop.expression.borrow() {
+                &Some(ref expression) => expression.clone(),
+                &None => return
+            },
+            None => return
+        };
+        if let Err(err) = self.set_property(&prop_ref.entity_id, &prop_ref.property_key, expression.clone()) {
+            self.defer_property(prop_ref, expression, err);
+        }
+    }
+    // Keeps a property pending with the error. The expression is still set, with the references
+    // that do resolve bound and only those as dependencies, so a fallback like `@gone.x ?? 5.0`
+    // evaluates in the meantime; the others read as ReferenceToNonExistentProperty.
+    fn defer_property(&mut self, prop_ref: &PropRef, expression: Pon, error: DocError) {
+        let mut resolved = expression.clone();
+        self.resolve_available_dependencies(&prop_ref.entity_id, &mut resolved);
+        let dependencies: Vec<PropRef> = {
+            let mut named_refs = vec![];
+            expression.get_dependency_references(&mut named_refs);
+            named_refs.iter()
+                .filter_map(|named_prop_ref| self.resolve_named_prop_ref(&prop_ref.entity_id, named_prop_ref).ok())
+                .collect()
+        };
+        if dependencies.iter().any(|dep| self.find_dependency_path(dep, prop_ref, &mut HashSet::new()).is_some()) {
+            self.unbind_property(prop_ref, expression, error);
+            return;
+        }
+        self.set_property_dependencies(prop_ref, dependencies);
+        {
+            let entity = self.entities.get_mut(&prop_ref.entity_id).unwrap();
+            let prop = entity.get_or_create_property(&prop_ref.property_key);
+            *prop.expression.borrow_mut() = Some(resolved);
+        }
+        self.pending_properties.push(PendingProperty {
+            prop_ref: prop_ref.clone(),
+            expression: expression,
+            error: error
+        });
+        if let &Some(ref cb) = &self.on_property_set {
+            cb(&prop_ref.entity_id, &prop_ref.property_key);
+        }
+    }
+    fn collect_subtree(&self, entity_id: &EntityId, ids: &mut Vec<EntityId>) {
+        ids.push(*entity_id);
+        if let Some(entity) = self.entities.get(entity_id) {
+            for child_id in &entity.children_ids {
+                self.collect_subtree(child_id, ids);
+            }
+        }
+    }
+    // Moves entity to be a child of parent_id, at index or last if index is None
+    pub fn set_parent(&mut self, entity_id: &EntityId, parent_id: &EntityId, index: Option<usize>) -> Result<(), DocError> {
+        let old_parent_id = match self.entities.get(entity_id) {
+            Some(entity) => match entity.parent_id {
+                Some(old_parent_id) => old_parent_id,
+                None => return Err(DocError::InvalidParent)
+            },
+            None => return Err(DocError::NoSuchEntity(*entity_id))
+        };
+        let n_children = match self.entities.get(parent_id) {
+            Some(parent) => parent.children_ids.len(),
+            None => return Err(DocError::NoSuchEntity(*parent_id))
+        };
+        let n_children = if old_parent_id == *parent_id { n_children - 1 } else { n_children };
+        let index = match index {
+            Some(index) if index <= n_children => index,
+            Some(index) => return Err(DocError::InvalidChildIndex(index)),
+            None => n_children
+        };
+        let mut subtree = vec![];
+        self.collect_subtree(entity_id, &mut subtree);
+        if subtree.contains(parent_id) {
+            return Err(DocError::InvalidParent);
+        }
+        self.entities.get_mut(&old_parent_id).unwrap().children_ids.retain(|id| id != entity_id);
+        self.entities.get_mut(parent_id).unwrap().children_ids.insert(index, *entity_id);
+        self.entities.get_mut(entity_id).unwrap().parent_id = Some(*parent_id);
+        self.rebind_tree_relative_properties()
+    }
+    pub fn insert_child_at(&mut self, parent_id: &EntityId, child_id: &EntityId, index: usize) -> Result<(), DocError> {
+        self.set_parent(child_id, parent_id, Some(index))
+    }
+    pub fn move_child(&mut self, parent_id: &EntityId, from_index: usize, to_index: usize) -> Result<(), DocError> {
+        {
+            let parent = match self.entities.get_mut(parent_id) {
+                Some(parent) => parent,
+                None => return Err(DocError::NoSuchEntity(*parent_id))
+            };
+            if from_index >= parent.children_ids.len() {
+                return Err(DocError::InvalidChildIndex(from_index));
+            }
+            if to_index >= parent.children_ids.len() {
+                return Err(DocError::InvalidChildIndex(to_index));
+            }
+            let child_id = parent.children_ids.remove(from_index);
+            parent.children_ids.insert(to_index, child_id);
+        }
+        self.rebind_tree_relative_properties()
+    }
+    // Re-resolves the properties referencing @parent or a search path whose targets changed when
+    // the tree was rearranged; properties that still resolve to the same targets are left alone.
+    // A property that no longer resolves is unset and kept pending, so it binds again once the
+    // tree makes it resolvable. The tree change is kept either way, and every property that
+    // failed to rebind is returned in the error.
+    fn rebind_tree_relative_properties(&mut self) -> Result<(), DocError> {
+        self.bind_pending_properties();
+        let mut to_rebind = vec![];
+        for (entity_id, entity) in self.entities.iter() {
+            for (property_key, prop) in entity.properties.iter() {
+                if let &Some(ref expression) = &*prop.expression.borrow() {
+                    let mut named_refs = vec![];
+                    expression.get_dependency_references(&mut named_refs);
+                    if !named_refs.iter().any(|named_ref| named_ref.entity_path.is_tree_relative()) {
+                        continue;
+                    }
+                    let changed = match self.build_property_node_dependencies(entity, expression) {
+                        Ok(dependencies) => {
+                            let mut unique_dependencies: Vec<PropRef> = vec![];
+                            for dep in dependencies {
+                                if !unique_dependencies.contains(&dep) {
+                                    unique_dependencies.push(dep);
+                                }
+                            }
+                            unique_dependencies != prop.dependencies
+                        },
+                        Err(_) => true
+                    };
+                    if changed {
+                        to_rebind.push((PropRef::new(entity_id, property_key), expression.clone()));
+                    }
+                }
+            }
+        }
+        let mut failures = vec![];
+        for (prop_ref, expression) in to_rebind {
+            if let Err(err) = self.set_property(&prop_ref.entity_id, &prop_ref.property_key, expression.clone()) {
+                self.unbind_property(&prop_ref, expression, err.clone());
+                failures.push((prop_ref, err));
+            }
+        }
+        if failures.len() > 0 {
+            Err(DocError::UnresolvedProperties(failures))
+        } else {
+            Ok(())
+        }
+    }
+    // Unsets a property that can't be resolved anymore, so it doesn't keep reading whatever its
+    // old dependencies pointed at, and keeps it pending with the error
+    fn unbind_property(&mut self, prop_ref: &PropRef, expression: Pon, error: DocError) {
+        if let Some(prop) = self.entities.get(&prop_ref.entity_id).and_then(|entity| entity.properties.get(&prop_ref.property_key)) {
+            *prop.expression.borrow_mut() = None;
+        }
+        self.set_property_dependencies(prop_ref, vec![]);
+        self.pending_properties.push(PendingProperty {
+            prop_ref: prop_ref.clone(),
+            expression: expression,
+            error: error
+        });
+        if let &Some(ref cb) = &self.on_property_set {
+            cb(&prop_ref.entity_id, &prop_ref.property_key);
+        }
+    }
     pub fn get_entity_by_name(&self, name: &str) -> Option<EntityId> {
         match self.entity_ids_by_name.get(&name.to_string()) {
             Some(id) => Some(id.clone()),
             None => None
         }
     }
+    pub fn has_entity(&self, entity_id: &EntityId) -> bool {
+        self.entities.contains_key(entity_id)
+    }
     pub fn entities_iter(&self) -> EntityIter {
         self.entities.keys()
     }
@@ -143,6 +523,7 @@ impl Document {
     // returns all props that were invalidated
     pub fn set_property(&mut self, entity_id: &EntityId, property_key: &str, mut expression: Pon) -> Result<(), DocError> {
         //println!("set property {} {:?}", property_key, expression);
+        self.pending_properties.retain(|pending| !(pending.prop_ref.entity_id == *entity_id && pending.prop_ref.property_key == property_key));
         let dependencies: Vec<PropRef> = {
             let entity = match self.entities.get(entity_id) {
                 Some(entity) => entity,
@@ -150,19 +531,20 @@ impl Document {
             };
             try!(self.build_property_node_dependencies(entity, &expression))
         };
-        for PropRef { entity_id: dep_ent_id, property_key: dep_prop_key } in dependencies {
-            match self.entities.get_mut(&dep_ent_id) {
-                Some(dep_ent) => {
-                    let prop_ref = PropRef { entity_id: entity_id.clone(), property_key: property_key.to_string() };
-                    let mut prop = dep_ent.get_or_create_property(&dep_prop_key);
-                    prop.dependants.push(prop_ref);
-                },
-                None => return Err(DocError::NoSuchEntity(dep_ent_id))
+        {
+            let prop_ref = PropRef::new(entity_id, property_key);
+            for dep in &dependencies {
+                if let Some(path) = self.find_dependency_path(dep, &prop_ref, &mut HashSet::new()) {
+                    let mut cycle = vec![prop_ref.clone()];
+                    cycle.extend(path);
+                    return Err(DocError::DependencyCycle(cycle));
+                }
             }
         }
         {
             try!(self.resolve_pon_dependencies(&entity_id, &mut expression));
         }
+        self.set_property_dependencies(&PropRef::new(entity_id, property_key), dependencies);
         {
             let mut ent_mut = self.entities.get_mut(entity_id).unwrap();
             let prop = ent_mut.get_or_create_property(property_key);
@@ -173,6 +555,85 @@ impl Document {
         }
         Ok(())
     }
+    // Like set_property, but if the expression refers to an entity by a name that doesn't exist
+    // yet, the property is kept pending and set again once an entity with that name is added
+    pub fn set_property_or_defer(&mut self, entity_id: &EntityId, property_key: &str, expression: Pon) -> Result<(), DocError> {
+        match self.set_property(entity_id, property_key, expression.clone()) {
+            Err(DocError::CantFindEntityByName(name)) => {
+                self.defer_property(&PropRef::new(entity_id, property_key), expression, DocError::CantFindEntityByName(name));
+                Ok(())
+            },
+            res => res
+        }
+    }
+    pub fn get_pending_properties(&self) -> Vec<(PropRef, DocError)> {
+        self.pending_properties.iter().map(|pending| (pending.prop_ref.clone(), pending.error.clone())).collect()
+    }
+    fn bind_pending_properties(&mut self) {
+        let pending_properties = mem::replace(&mut self.pending_properties, vec![]);
+        for mut pending in pending_properties {
+            match self.set_property(&pending.prop_ref.entity_id, &pending.prop_ref.property_key, pending.expression.clone()) {
+                Ok(()) => {},
+                Err(err) => {
+                    pending.error = err;
+                    self.pending_properties.push(pending);
+                }
+            }
+        }
+    }
+    // Puts the property back into its unset state. Dependants keep pointing at it, but will get
+    // a ReferenceToNonExistentProperty until it is set again.
+    // A property that is still pending is dropped too, so it isn't set later on.
+    pub fn remove_property(&mut self, entity_id: &EntityId, property_key: &str) -> Result<(), DocError> {
+        let prop_ref = PropRef::new(entity_id, property_key);
+        let was_pending = self.pending_properties.iter().any(|pending| pending.prop_ref == prop_ref);
+        {
+            let entity = match self.entities.get(entity_id) {
+                Some(entity) => entity,
+                None => return Err(DocError::NoSuchEntity(*entity_id))
+            };
+            let had_expression = match entity.properties.get(property_key) {
+                Some(prop) => prop.expression.borrow_mut().take().is_some(),
+                None => false
+            };
+            if !had_expression && !was_pending {
+                return Err(DocError::NoSuchProperty(property_key.to_string()));
+            }
+        }
+        self.pending_properties.retain(|pending| pending.prop_ref != prop_ref);
+        self.set_property_dependencies(&prop_ref, vec![]);
+        if let &Some(ref cb) = &self.on_property_set {
+            cb(entity_id, property_key);
+        }
+        Ok(())
+    }
+    // Replaces the outgoing edges of prop_ref, and updates the dependants of both the old and the
+    // new dependencies to match. All dependencies must point at existing entities.
+    fn set_property_dependencies(&mut self, prop_ref: &PropRef, dependencies: Vec<PropRef>) {
+        let mut unique_dependencies: Vec<PropRef> = vec![];
+        for dep in dependencies {
+            if !unique_dependencies.contains(&dep) {
+                unique_dependencies.push(dep);
+            }
+        }
+        let old_dependencies = {
+            let entity = self.entities.get_mut(&prop_ref.entity_id).unwrap();
+            let prop = entity.get_or_create_property(&prop_ref.property_key);
+            mem::replace(&mut prop.dependencies, unique_dependencies.clone())
+        };
+        for dep in old_dependencies {
+            if let Some(dep_ent) = self.entities.get_mut(&dep.entity_id) {
+                if let Some(dep_prop) = dep_ent.properties.get_mut(&dep.property_key) {
+                    dep_prop.dependants.retain(|x| x != prop_ref);
+                }
+            }
+        }
+        for dep in unique_dependencies {
+            let dep_ent = self.entities.get_mut(&dep.entity_id).unwrap();
+            let dep_prop = dep_ent.get_or_create_property(&dep.property_key);
+            dep_prop.dependants.push(prop_ref.clone());
+        }
+    }
     pub fn get_property(&self, entity_id: &EntityId, property_key: &str) -> Result<Ref<Pon>, DocError> {
         match self.entities.get(entity_id) {
             Some(entity) => self.get_entity_property(entity, property_key),
@@ -223,7 +684,10 @@ impl Document {
         match path {
             &EntityPath::This => Ok(*start_entity_id),
             &EntityPath::Parent => match self.entities.get(start_entity_id) {
-                Some(entity) => Ok(entity.parent_id.unwrap().clone()),
+                Some(entity) => match entity.parent_id {
+                    Some(parent_id) => Ok(parent_id),
+                    None => Err(DocError::InvalidParent)
+                },
                 None => Err(DocError::NoSuchEntity(*start_entity_id))
             },
             &EntityPath::Named(ref name) => match self.entity_ids_by_name.get(name) {
@@ -249,29 +713,27 @@ impl Document {
         }
     }
 
+    // The plain loaders drop the diagnostics, use the _with_report variants to get them
     pub fn from_file(path: &Path) -> Result<Document, DocError> {
-        let mut doc = Document::new();
-        let mut warnings = vec![];
-        try!(doc.append_from_event_reader(&mut vec![], event_reader_from_file(path).events(), &mut warnings));
-        if warnings.len() > 0 {
-            println!("{} WARNINGS PARSING DOCUMENT:", warnings.len());
-            println!("{}", warnings.join("\n"));
-        }
-        Ok(doc)
+        Document::load_with_report(path, &DocumentLoadOptions::default()).map(|(doc, _)| doc)
     }
     pub fn from_string(string: &str) -> Result<Document, DocError> {
+        Document::from_string_with_report(string, &DocumentLoadOptions::default()).map(|(doc, _)| doc)
+    }
+    pub fn load_with_report(path: &Path, options: &DocumentLoadOptions) -> Result<(Document, DocumentLoadReport), DocError> {
+        Document::load_from_event_reader(&mut try!(event_reader_from_file(path)), options)
+    }
+    pub fn from_string_with_report(string: &str, options: &DocumentLoadOptions) -> Result<(Document, DocumentLoadReport), DocError> {
+        Document::load_from_event_reader(&mut EventReader::from_str(string), options)
+    }
+    fn load_from_event_reader<R: Read>(parser: &mut EventReader<R>, options: &DocumentLoadOptions) -> Result<(Document, DocumentLoadReport), DocError> {
         let mut doc = Document::new();
-        let mut parser = EventReader::from_str(string);
-        let mut warnings = vec![];
-        try!(doc.append_from_event_reader(&mut vec![], parser.events(), &mut warnings));
-        if warnings.len() > 0 {
-            println!("{} WARNINGS PARSING DOCUMENT:", warnings.len());
-            println!("{}", warnings.join("\n"));
-        }
-        Ok(doc)
+        let mut report = DocumentLoadReport { diagnostics: vec![] };
+        try!(doc.append_from_event_reader(&mut vec![], parser, &mut report));
+        try!(report.check_strict(options));
+        Ok((doc, report))
     }
 
-
     fn build_property_node_dependencies(&self, entity: &Entity, node: &Pon) -> Result<Vec<PropRef>, DocError> {
         let mut named_refs = vec![];
         node.get_dependency_references(&mut named_refs);
@@ -292,23 +754,123 @@ impl Document {
         }
     }
 
-    fn resolve_pon_dependencies(&mut self, entity_id: &EntityId, node: &mut Pon) -> Result<(), DocError> {
+    pub fn dependencies_of(&self, prop_ref: &PropRef) -> Result<&Vec<PropRef>, DocError> {
+        match self.entities.get(&prop_ref.entity_id) {
+            Some(entity) => match entity.properties.get(&prop_ref.property_key) {
+                Some(prop) => Ok(&prop.dependencies),
+                None => Err(DocError::NoSuchProperty(prop_ref.property_key.to_string()))
+            },
+            None => Err(DocError::NoSuchEntity(prop_ref.entity_id))
+        }
+    }
+
+    // The dependencies a property's current value actually depends on, see
+    // Pon::get_active_dependency_references. References that don't resolve are left out.
+    pub fn active_dependencies_of(&self, prop_ref: &PropRef) -> Result<Vec<PropRef>, DocError> {
+        let expression = try!(self.get_property(&prop_ref.entity_id, &prop_ref.property_key));
+        let mut named_refs = vec![];
+        expression.get_active_dependency_references(&mut named_refs);
+        Ok(named_refs.iter()
+            .filter_map(|named_prop_ref| self.resolve_named_prop_ref(&prop_ref.entity_id, named_prop_ref).ok())
+            .collect())
+    }
+
+    // Finds a chain of dependencies leading from `from` to `to`, including `from` but not `to`
+    fn find_dependency_path(&self, from: &PropRef, to: &PropRef, visited: &mut HashSet<PropRef>) -> Option<Vec<PropRef>> {
+        if from == to {
+            return Some(vec![]);
+        }
+        if !visited.insert(from.clone()) {
+            return None;
+        }
+        if let Ok(deps) = self.dependencies_of(from) {
+            for dep in deps {
+                if let Some(mut path) = self.find_dependency_path(dep, to, visited) {
+                    path.insert(0, from.clone());
+                    return Some(path);
+                }
+            }
+        }
+        None
+    }
+
+    // Returns every dependency loop in the document, each listed in dependency order
+    pub fn find_cycles(&self) -> Vec<Vec<PropRef>> {
+        let mut cycles = vec![];
+        let mut done = HashSet::new();
+        let mut stack = vec![];
+        for (entity_id, entity) in self.entities.iter() {
+            for property_key in entity.properties.keys() {
+                self.find_cycles_from(&PropRef::new(entity_id, property_key), &mut stack, &mut done, &mut cycles);
+            }
+        }
+        cycles
+    }
+    fn find_cycles_from(&self, prop_ref: &PropRef, stack: &mut Vec<PropRef>, done: &mut HashSet<PropRef>, cycles: &mut Vec<Vec<PropRef>>) {
+        if done.contains(prop_ref) {
+            return;
+        }
+        if let Some(position) = stack.iter().position(|x| x == prop_ref) {
+            cycles.push(stack[position..].to_vec());
+            return;
+        }
+        stack.push(prop_ref.clone());
+        if let Ok(deps) = self.dependencies_of(prop_ref) {
+            for dep in deps {
+                self.find_cycles_from(dep, stack, done, cycles);
+            }
+        }
+        stack.pop();
+        done.insert(prop_ref.clone());
+    }
+
+    fn resolve_dependency_reference(&mut self, entity_id: &EntityId, named_prop_ref: &NamedPropRef, resolved: &mut Option<ResolvedDependency>) -> Result<(), DocError> {
+        let prop_ref = try!(self.resolve_named_prop_ref(&entity_id, &named_prop_ref));
+        match self.entities.get_mut(&prop_ref.entity_id) {
+            Some(entity) => {
+                let prop = entity.get_or_create_property(&prop_ref.property_key);
+                *resolved = Some(ResolvedDependency {
+                    prop_ref: prop_ref,
+                    value: prop.expression.clone()
+                });
+                Ok(())
+            },
+            None => Err(DocError::NoSuchEntity(prop_ref.entity_id))
+        }
+    }
+    // Like resolve_pon_dependencies, but references that don't resolve are skipped and keep
+    // whatever they were bound to before
+    fn resolve_available_dependencies(&mut self, entity_id: &EntityId, node: &mut Pon) {
         match node {
-            &mut Pon::TypedPon(box TypedPon { ref mut data, .. }) =>
-                try!(self.resolve_pon_dependencies(entity_id, data)),
             &mut Pon::DependencyReference(ref named_prop_ref, ref mut resolved) => {
-                let prop_ref = try!(self.resolve_named_prop_ref(&entity_id, &named_prop_ref));
-                match self.entities.get_mut(&prop_ref.entity_id) {
-                    Some(entity) => {
-                        let prop = entity.get_or_create_property(&prop_ref.property_key);
-                        *resolved = Some(ResolvedDependency {
-                            prop_ref: prop_ref,
-                            value: prop.expression.clone()
-                        });
-                    },
-                    None => return Err(DocError::NoSuchEntity(prop_ref.entity_id))
+                let _ = self.resolve_dependency_reference(entity_id, named_prop_ref, resolved);
+            },
+            &mut Pon::TypedPon(box TypedPon { ref mut data, .. }) =>
+                self.resolve_available_dependencies(entity_id, data),
+            &mut Pon::Object(ref mut hm) => {
+                for (_, v) in hm.iter_mut() {
+                    self.resolve_available_dependencies(entity_id, v)
                 }
             },
+            &mut Pon::Array(ref mut arr) => {
+                for v in arr.iter_mut() {
+                    self.resolve_available_dependencies(entity_id, v)
+                }
+            },
+            &mut Pon::Expression(box ref mut expression) => {
+                for v in expression.operands_mut() {
+                    self.resolve_available_dependencies(entity_id, v)
+                }
+            },
+            _ => {}
+        }
+    }
+    fn resolve_pon_dependencies(&mut self, entity_id: &EntityId, node: &mut Pon) -> Result<(), DocError> {
+        match node {
+            &mut Pon::TypedPon(box TypedPon { ref mut data, .. }) =>
+                try!(self.resolve_pon_dependencies(entity_id, data)),
+            &mut Pon::DependencyReference(ref named_prop_ref, ref mut resolved) =>
+                try!(self.resolve_dependency_reference(entity_id, named_prop_ref, resolved)),
             &mut Pon::Object(ref mut hm) => {
                 for (_, v) in hm.iter_mut() {
                     try!(self.resolve_pon_dependencies(entity_id, v))
@@ -319,6 +881,11 @@ impl Document {
                     try!(self.resolve_pon_dependencies(entity_id, v))
                 }
             },
+            &mut Pon::Expression(box ref mut expression) => {
+                for v in expression.operands_mut() {
+                    try!(self.resolve_pon_dependencies(entity_id, v))
+                }
+            },
             _ => {}
         };
         Ok(())
@@ -337,8 +904,15 @@ impl Document {
         }
     }
 
-    fn append_from_event_reader<T: Iterator<Item=XmlEvent>>(&mut self, mut entity_stack: &mut Vec<EntityId>, mut events: T, warnings: &mut Vec<String>) -> Result<(), DocError> {
-        while let Some(e) = events.next() {
+    fn append_from_event_reader<R: Read>(&mut self, entity_stack: &mut Vec<EntityId>, parser: &mut EventReader<R>, report: &mut DocumentLoadReport) -> Result<(), DocError> {
+        // Deferred properties and where they came from, so that the ones that never get
+        // resolved can be reported with a position at the end
+        let mut deferred = HashMap::new();
+        loop {
+            // where the reader is before the event is read, i.e. where the element starts
+            let line = parser.row() + 1;
+            let column = parser.col() + 1;
+            let e = parser.next();
             match e {
                 XmlEvent::StartElement { name: type_name, attributes, .. } => {
                     let entity_name = match attributes.iter().find(|x| x.name.local_name == "name") {
@@ -349,22 +923,38 @@ impl Document {
                         Some(parent) => Some(*parent),
                         None => None
                     };
-                    let entity_id = match self.append_entity(parent, &type_name.local_name, entity_name) {
+                    let entity_id = match self.append_entity(parent, &type_name.local_name, entity_name.clone()) {
                         Ok(id) => id,
                         Err(err) => {
-                            warnings.push(format!("Failed to append entity {:?}: {:?}", type_name.local_name, err));
+                            report.diagnostics.push(LoadDiagnostic {
+                                kind: LoadDiagnosticKind::AppendEntityFailed,
+                                entity_type_name: type_name.local_name.to_string(),
+                                entity_name: entity_name,
+                                attribute: None,
+                                text: "".to_string(),
+                                message: format!("{:?}", err),
+                                line: line,
+                                column: column
+                            });
                             continue;
                         }
                     };
 
                     for attribute in attributes {
                         if attribute.name.local_name == "name" { continue; }
+                        let diagnostic = |kind, message| LoadDiagnostic {
+                            kind: kind,
+                            entity_type_name: type_name.local_name.to_string(),
+                            entity_name: entity_name.clone(),
+                            attribute: Some(attribute.name.local_name.to_string()),
+                            text: attribute.value.to_string(),
+                            message: message,
+                            line: line,
+                            column: column
+                        };
                         match Pon::from_string(&attribute.value) {
-                            Ok(node) => match self.set_property(&entity_id, &attribute.name.local_name, node) {
-                                Ok(_) => {},
-                                Err(err) => warnings.push(format!("Failed to set property {} for entity {:?}: {:?}", attribute.name.local_name, type_name.local_name, err))
-                            },
-                            Err(err) => warnings.push(format!("Error parsing property {} of entity {:?}: {} with error: {:?}", attribute.name.local_name, type_name.local_name, attribute.value, err))
+                            Ok(node) => self.load_property(&entity_id, &attribute.name.local_name, node, &diagnostic, &mut deferred, report),
+                            Err(err) => report.diagnostics.push(diagnostic(LoadDiagnosticKind::PropertyParseError, err.to_string()))
                         };
                     }
                     entity_stack.push(entity_id);
@@ -372,68 +962,347 @@ impl Document {
                 XmlEvent::EndElement { .. } => {
                     entity_stack.pop();
                 }
-                XmlEvent::Error(e) => {
-                    warnings.push(format!("Xml parsing error: {}", e));
-                }
+                XmlEvent::EndDocument => break,
+                XmlEvent::Error(e) => return Err(DocError::Xml(format!("{}", e))),
                 _ => {}
             }
         }
+        self.report_unresolved_properties(deferred, report);
         Ok(())
     }
 
-    fn entity_to_xml<T: Write>(&self, entity_id: &EntityId, writer: &mut xml::writer::EventWriter<T>) {
+    // Sets a property read by one of the loaders. Failures go in the report, and properties that
+    // had to be deferred are kept in `deferred` with their diagnostic, to be reported by
+    // report_unresolved_properties if they never get resolved.
+    fn load_property(&mut self, entity_id: &EntityId, property_key: &str, expression: Pon, diagnostic: &Fn(LoadDiagnosticKind, String) -> LoadDiagnostic,
+                     deferred: &mut HashMap<PropRef, LoadDiagnostic>, report: &mut DocumentLoadReport) {
+        match self.set_property_or_defer(entity_id, property_key, expression) {
+            Ok(_) => {
+                let prop_ref = PropRef::new(entity_id, property_key);
+                if self.pending_properties.iter().any(|pending| pending.prop_ref == prop_ref) {
+                    deferred.insert(prop_ref, diagnostic(LoadDiagnosticKind::UnresolvedProperty, "".to_string()));
+                }
+            },
+            Err(err) => report.diagnostics.push(diagnostic(LoadDiagnosticKind::SetPropertyFailed, format!("{:?}", err)))
+        }
+    }
+    fn report_unresolved_properties(&self, mut deferred: HashMap<PropRef, LoadDiagnostic>, report: &mut DocumentLoadReport) {
+        for (prop_ref, err) in self.get_pending_properties() {
+            if let Some(mut diagnostic) = deferred.remove(&prop_ref) {
+                diagnostic.message = format!("{:?}", err);
+                report.diagnostics.push(diagnostic);
+            }
+        }
+    }
+
+    pub fn from_scene_string(string: &str) -> Result<Document, DocError> {
+        Document::from_scene_string_with_report(string, &DocumentLoadOptions::default()).map(|(doc, _)| doc)
+    }
+    pub fn load_scene(path: &Path) -> Result<Document, DocError> {
+        Document::load_scene_with_report(path, &DocumentLoadOptions::default()).map(|(doc, _)| doc)
+    }
+    pub fn load_scene_with_report(path: &Path, options: &DocumentLoadOptions) -> Result<(Document, DocumentLoadReport), DocError> {
+        let mut string = String::new();
+        match File::open(path).and_then(|mut file| file.read_to_string(&mut string)) {
+            Ok(_) => Document::from_scene_string_with_report(&string, options),
+            Err(err) => Err(DocError::Io(path.to_path_buf(), err.to_string()))
+        }
+    }
+    // Loads a scene written in pon, e.g. `Entity name=tmp { x: 5.0, children: [Sprite { y: @tmp.x }] }`.
+    // A syntax error anywhere fails the whole load, everything else is reported like for xml.
+    pub fn from_scene_string_with_report(string: &str, options: &DocumentLoadOptions) -> Result<(Document, DocumentLoadReport), DocError> {
+        let scene = match SceneEntity::from_string(string) {
+            Ok(scene) => scene,
+            Err(err) => return Err(DocError::ParseError(err))
+        };
+        let mut doc = Document::new();
+        let mut report = DocumentLoadReport { diagnostics: vec![] };
+        if let Some(ref root) = scene {
+            let mut deferred = HashMap::new();
+            doc.append_from_scene(None, root, string, &mut deferred, &mut report);
+            doc.report_unresolved_properties(deferred, &mut report);
+        }
+        try!(report.check_strict(options));
+        Ok((doc, report))
+    }
+    fn append_from_scene(&mut self, parent_id: Option<EntityId>, scene: &SceneEntity, source: &str, deferred: &mut HashMap<PropRef, LoadDiagnostic>, report: &mut DocumentLoadReport) {
+        let (line, column) = source_position(source, scene.offset);
+        let entity_id = match self.append_entity(parent_id, &scene.type_name, scene.name.clone()) {
+            Ok(id) => id,
+            Err(err) => {
+                report.diagnostics.push(LoadDiagnostic {
+                    kind: LoadDiagnosticKind::AppendEntityFailed,
+                    entity_type_name: scene.type_name.to_string(),
+                    entity_name: scene.name.clone(),
+                    attribute: None,
+                    text: "".to_string(),
+                    message: format!("{:?}", err),
+                    line: line,
+                    column: column
+                });
+                return;
+            }
+        };
+        for &(ref key, ref expression, offset) in &scene.properties {
+            let (line, column) = source_position(source, offset);
+            let diagnostic = |kind, message| LoadDiagnostic {
+                kind: kind,
+                entity_type_name: scene.type_name.to_string(),
+                entity_name: scene.name.clone(),
+                attribute: Some(key.to_string()),
+                text: expression.to_string(),
+                message: message,
+                line: line,
+                column: column
+            };
+            self.load_property(&entity_id, key, expression.clone(), &diagnostic, deferred, report);
+        }
+        for child in &scene.children {
+            self.append_from_scene(Some(entity_id), child, source, deferred, report);
+        }
+    }
+
+    pub fn snapshot(&self) -> DocumentSnapshot {
+        DocumentSnapshot {
+            root: self.root.map(|root| self.entity_snapshot(&root))
+        }
+    }
+    fn entity_snapshot(&self, entity_id: &EntityId) -> EntitySnapshot {
+        let entity = self.entities.get(entity_id).unwrap();
+        let mut properties: Vec<(String, Pon)> = entity.properties.iter().filter_map(|(key, prop)| {
+            match &*prop.expression.borrow() {
+                &Some(ref expression) => {
+                    let mut expression = expression.clone();
+                    clear_resolved_dependencies(&mut expression);
+                    Some((key.clone(), expression))
+                },
+                &None => None
+            }
+        }).collect();
+        properties.sort_by(|a, b| a.0.cmp(&b.0));
+        EntitySnapshot {
+            type_name: entity.type_name.clone(),
+            name: entity.name.clone(),
+            properties: properties,
+            children: entity.children_ids.iter().map(|child_id| self.entity_snapshot(child_id)).collect()
+        }
+    }
+    pub fn from_snapshot(snapshot: &DocumentSnapshot) -> Result<Document, DocError> {
+        Document::from_snapshot_with_report(snapshot, &DocumentLoadOptions::default()).map(|(doc, _)| doc)
+    }
+    // Problems are reported like for xml, without a position since a snapshot doesn't have any
+    pub fn from_snapshot_with_report(snapshot: &DocumentSnapshot, options: &DocumentLoadOptions) -> Result<(Document, DocumentLoadReport), DocError> {
+        let mut doc = Document::new();
+        let mut report = DocumentLoadReport { diagnostics: vec![] };
+        if let Some(ref root) = snapshot.root {
+            let mut deferred = HashMap::new();
+            doc.load_snapshot(None, root, &mut deferred, &mut report);
+            doc.report_unresolved_properties(deferred, &mut report);
+        }
+        try!(report.check_strict(options));
+        Ok((doc, report))
+    }
+    fn load_snapshot(&mut self, parent_id: Option<EntityId>, snapshot: &EntitySnapshot, deferred: &mut HashMap<PropRef, LoadDiagnostic>, report: &mut DocumentLoadReport) {
+        let entity_id = match self.append_entity(parent_id, &snapshot.type_name, snapshot.name.clone()) {
+            Ok(id) => id,
+            Err(err) => {
+                report.diagnostics.push(LoadDiagnostic {
+                    kind: LoadDiagnosticKind::AppendEntityFailed,
+                    entity_type_name: snapshot.type_name.to_string(),
+                    entity_name: snapshot.name.clone(),
+                    attribute: None,
+                    text: "".to_string(),
+                    message: format!("{:?}", err),
+                    line: 0,
+                    column: 0
+                });
+                return;
+            }
+        };
+        for &(ref key, ref expression) in &snapshot.properties {
+            let diagnostic = |kind, message| LoadDiagnostic {
+                kind: kind,
+                entity_type_name: snapshot.type_name.to_string(),
+                entity_name: snapshot.name.clone(),
+                attribute: Some(key.to_string()),
+                text: expression.to_string(),
+                message: message,
+                line: 0,
+                column: 0
+            };
+            self.load_property(&entity_id, key, expression.clone(), &diagnostic, deferred, report);
+        }
+        for child in &snapshot.children {
+            self.load_snapshot(Some(entity_id), child, deferred, report);
+        }
+    }
+    // Adds the entity and its children. Properties referring to entities that aren't there yet
+    // are deferred, the same way as when loading xml.
+    pub fn append_snapshot(&mut self, parent_id: Option<EntityId>, snapshot: &EntitySnapshot) -> Result<EntityId, DocError> {
+        let entity_id = try!(self.append_entity(parent_id, &snapshot.type_name, snapshot.name.clone()));
+        for &(ref key, ref expression) in &snapshot.properties {
+            try!(self.set_property_or_defer(&entity_id, key, expression.clone()));
+        }
+        for child in &snapshot.children {
+            try!(self.append_snapshot(Some(entity_id), child));
+        }
+        Ok(entity_id)
+    }
+
+    pub fn save(&self, path: &Path, options: &DocumentWriteOptions) -> Result<(), DocError> {
+        let mut file = match File::create(path) {
+            Ok(file) => file,
+            Err(err) => return Err(DocError::Io(path.to_path_buf(), err.to_string()))
+        };
+        self.write_to(&mut file, options)
+    }
+    pub fn write_to<W: Write>(&self, sink: &mut W, options: &DocumentWriteOptions) -> Result<(), DocError> {
+        let mut config = xml::writer::EmitterConfig::new();
+        if let Some(ref indent) = options.indent {
+            config.perform_indent = true;
+            config.indent_string = indent.to_string();
+        }
+        let mut writer = xml::writer::EventWriter::new_with_config(sink, config);
+        try!(write_xml_event(&mut writer, xml::writer::events::XmlEvent::StartDocument {
+            version: options.xml_version,
+            encoding: None,
+            standalone: None
+        }));
+        if let Some(root) = self.root {
+            try!(self.entity_to_xml(&root, &mut writer, options));
+        }
+        Ok(())
+    }
+    fn entity_to_xml<T: Write>(&self, entity_id: &EntityId, writer: &mut xml::writer::EventWriter<T>, options: &DocumentWriteOptions) -> Result<(), DocError> {
         let entity = self.entities.get(entity_id).unwrap();
         let type_name = xml::name::Name::local(&entity.type_name);
+        // Attribute values are kept on one line, line breaks don't survive attribute normalization
+        let pon_options = PonStringifyOptions {
+            unwrap_dependencies: options.unwrap_dependencies,
+            max_line_width: ::std::usize::MAX,
+            ..PonStringifyOptions::default()
+        };
         let mut attrs: Vec<xml::attribute::OwnedAttribute> = entity.properties.iter().filter_map(|(name, prop)| {
             match &*prop.expression.borrow() {
                 &Some(ref expression) => Some(xml::attribute::OwnedAttribute {
                     name: xml::name::OwnedName::local(name.to_string()),
-                    value: expression.to_string()
+                    value: expression.to_string_with(&pon_options)
                 }),
                 &None => None
             }
         }).collect();
+        attrs.sort_by(|a, b| a.name.local_name.cmp(&b.name.local_name) );
         if let &Some(ref name) = &entity.name {
-            attrs.push(xml::attribute::OwnedAttribute {
+            let name_attr = xml::attribute::OwnedAttribute {
                 name: xml::name::OwnedName::local("name"),
                 value: name.to_string()
-            });
+            };
+            match options.attribute_order {
+                AttributeOrder::Alphabetical => {
+                    let index = attrs.iter().position(|a| a.name.local_name.as_str() > "name").unwrap_or(attrs.len());
+                    attrs.insert(index, name_attr);
+                },
+                AttributeOrder::NameFirst => attrs.insert(0, name_attr)
+            }
         }
-        attrs.sort_by(|a, b| a.name.local_name.cmp(&b.name.local_name) );
-        writer.write(xml::writer::events::XmlEvent::StartElement {
+        try!(write_xml_event(writer, xml::writer::events::XmlEvent::StartElement {
             name: type_name.clone(),
             attributes: attrs.iter().map(|x| x.borrow()).collect(),
             namespace: &xml::namespace::Namespace::empty()
-        }).unwrap();
+        }));
         for e in &entity.children_ids {
-            self.entity_to_xml(e, writer);
+            try!(self.entity_to_xml(e, writer, options));
         }
-        writer.write(xml::writer::events::XmlEvent::EndElement {
+        write_xml_event(writer, xml::writer::events::XmlEvent::EndElement {
             name: type_name.clone()
-        }).unwrap();
+        })
     }
-    fn to_xml(&self) -> String {
-        let mut buff = vec![];
-        {
-            let mut writer = xml::writer::EventWriter::new(&mut buff);
-            writer.write(xml::writer::events::XmlEvent::StartDocument {
-                version: xml::common::XmlVersion::Version11,
-                encoding: None,
-                standalone: None
-            }).unwrap();
-            if self.root.is_some() {
-                self.entity_to_xml(&self.root.unwrap(), &mut writer);
+    pub fn save_scene(&self, path: &Path, options: &PonStringifyOptions) -> Result<(), DocError> {
+        match File::create(path).and_then(|mut file| file.write_all(self.to_scene_string_with(options).as_bytes())) {
+            Ok(()) => Ok(()),
+            Err(err) => Err(DocError::Io(path.to_path_buf(), err.to_string()))
+        }
+    }
+    pub fn to_scene_string(&self) -> String {
+        self.to_scene_string_with(&PonStringifyOptions::default())
+    }
+    pub fn to_scene_string_with(&self, options: &PonStringifyOptions) -> String {
+        match self.root {
+            Some(root) => format!("{}\n", self.entity_to_scene(&root).to_string_with(options)),
+            None => "".to_string()
+        }
+    }
+    // Properties are sorted by key, like in a snapshot
+    fn entity_to_scene(&self, entity_id: &EntityId) -> SceneEntity {
+        let entity = self.entities.get(entity_id).unwrap();
+        let mut properties: Vec<(String, Pon, usize)> = entity.properties.iter().filter_map(|(key, prop)| {
+            match &*prop.expression.borrow() {
+                &Some(ref expression) => Some((key.clone(), expression.clone(), 0)),
+                &None => None
             }
+        }).collect();
+        properties.sort_by(|a, b| a.0.cmp(&b.0));
+        SceneEntity {
+            type_name: entity.type_name.clone(),
+            name: entity.name.clone(),
+            properties: properties,
+            children: entity.children_ids.iter().map(|child_id| self.entity_to_scene(child_id)).collect(),
+            offset: 0
         }
+    }
+    fn to_xml(&self) -> String {
+        let mut buff = vec![];
+        self.write_to(&mut buff, &DocumentWriteOptions::default()).unwrap();
         String::from_utf8(buff).unwrap()
     }
 }
 
-fn event_reader_from_file(path: &Path) -> EventReader<BufReader<File>> {
-    let file = File::open(path).unwrap();
+fn clear_resolved_dependencies(node: &mut Pon) {
+    match node {
+        &mut Pon::TypedPon(box TypedPon { ref mut data, .. }) => clear_resolved_dependencies(data),
+        &mut Pon::DependencyReference(_, ref mut resolved) => *resolved = None,
+        &mut Pon::Object(ref mut hm) => {
+            for (_, v) in hm.iter_mut() {
+                clear_resolved_dependencies(v);
+            }
+        },
+        &mut Pon::Array(ref mut arr) => {
+            for v in arr.iter_mut() {
+                clear_resolved_dependencies(v);
+            }
+        },
+        &mut Pon::Expression(box ref mut expression) => {
+            for v in expression.operands_mut() {
+                clear_resolved_dependencies(v);
+            }
+        },
+        _ => {}
+    }
+}
+
+// Line and column, both starting at 1, of a byte offset into the source
+fn source_position(source: &str, offset: usize) -> (u64, u64) {
+    let before = &source[..offset];
+    let line = before.matches('\n').count() as u64 + 1;
+    let column = before.chars().rev().take_while(|c| *c != '\n').count() as u64 + 1;
+    (line, column)
+}
+
+fn write_xml_event<T: Write>(writer: &mut xml::writer::EventWriter<T>, event: xml::writer::events::XmlEvent) -> Result<(), DocError> {
+    match writer.write(event) {
+        Ok(()) => Ok(()),
+        Err(err) => Err(DocError::Xml(format!("{:?}", err)))
+    }
+}
+
+fn event_reader_from_file(path: &Path) -> Result<EventReader<BufReader<File>>, DocError> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) => return Err(DocError::Io(path.to_path_buf(), err.to_string()))
+    };
     let file = BufReader::new(file);
 
-    EventReader::new(file)
+    Ok(EventReader::new(file))
 }
 
 impl ToString for Document {
@@ -493,7 +1362,8 @@ fn test_property_reference_array() {
 fn test_property_reference_bad_ref() {
     let doc = Document::from_string(r#"<Entity name="tmp" x="5.0" y="@what.x" />"#).unwrap();
     let ent = doc.get_entity_by_name("tmp").unwrap();
-    assert_eq!(doc.get_property(&ent, "y").err().unwrap(), DocError::NoSuchProperty("y".to_string()));
+    assert_eq!(doc.get_property(&ent, "y").unwrap().concretize().err().unwrap(),
+        PonTranslateErr::ReferenceToNonExistentProperty(NamedPropRef::new(EntityPath::Named("what".to_string()), "x")));
 }
 
 #[test]
@@ -530,3 +1400,551 @@ fn test_document_to_string_empty() {
     let doc = Document::new();
     assert_eq!(doc.to_string(), "<?xml version=\"1.1\" encoding=\"UTF-8\"?>");
 }
+
+#[test]
+fn test_remove_entity() {
+    let mut doc = Document::from_string(r#"<Entity name="root"><Entity name="tmp"><Entity name="child" /></Entity></Entity>"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    let child = doc.get_entity_by_name("child").unwrap();
+    doc.remove_entity(&ent).unwrap();
+    assert_eq!(doc.get_entity_by_name("tmp"), None);
+    assert_eq!(doc.get_entity_by_name("child"), None);
+    assert_eq!(doc.get_children(&root).unwrap().len(), 0);
+    assert_eq!(doc.get_entity_type_name(&child).err().unwrap(), DocError::NoSuchEntity(child));
+}
+
+#[test]
+fn test_remove_entity_shadowed_name() {
+    let mut doc = Document::from_string(r#"<Entity name="root"><Entity name="tmp" /></Entity>"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    let first = doc.get_entity_by_name("tmp").unwrap();
+    let second = doc.append_entity(Some(root), "Entity", Some("tmp".to_string())).unwrap();
+    assert_eq!(doc.get_entity_by_name("tmp"), Some(second));
+    doc.remove_entity(&first).unwrap();
+    assert_eq!(doc.get_entity_by_name("tmp"), Some(second));
+    doc.remove_entity(&second).unwrap();
+    assert_eq!(doc.get_entity_by_name("tmp"), None);
+}
+
+#[test]
+fn test_remove_entity_root() {
+    let mut doc = Document::from_string(r#"<Entity name="root" />"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    doc.remove_entity(&root).unwrap();
+    assert_eq!(doc.get_root(), None);
+    assert_eq!(doc.entities_iter().count(), 0);
+}
+
+#[test]
+fn test_remove_entity_dependency() {
+    let mut doc = Document::from_string(r#"<Entity><Entity name="tmp" x="5.0" /><Entity name="other" y="@tmp.x" /></Entity>"#).unwrap();
+    let other = doc.get_entity_by_name("other").unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    doc.remove_entity(&ent).unwrap();
+    assert_eq!(doc.get_property(&other, "y").unwrap().concretize().err().unwrap(),
+        PonTranslateErr::ReferenceToNonExistentProperty(NamedPropRef::new(EntityPath::Named("tmp".to_string()), "x")));
+}
+
+#[test]
+fn test_remove_entity_prunes_dependants() {
+    let mut doc = Document::from_string(r#"<Entity name="root" x="5.0"><Entity name="tmp" y="@parent.x" /></Entity>"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    doc.remove_entity(&ent).unwrap();
+    assert_eq!(doc.get_property_dependants(&root, "x").unwrap().len(), 0);
+}
+
+#[test]
+fn test_pending_property_fallback() {
+    let mut doc = Document::from_string(r#"<Entity name="root" x="2.0" y="@tmp.x ?? @this.x" />"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    assert_eq!(doc.get_pending_properties(), vec![(PropRef::new(&root, "y"), DocError::CantFindEntityByName("tmp".to_string()))]);
+    assert_eq!(doc.dependencies_of(&PropRef::new(&root, "y")).unwrap(), &vec![PropRef::new(&root, "x")]);
+    assert_eq!(doc.get_property(&root, "y").unwrap().concretize().unwrap(), Pon::Float(2.0));
+    doc.set_property(&root, "x", Pon::Float(3.0)).unwrap();
+    assert_eq!(doc.get_property(&root, "y").unwrap().concretize().unwrap(), Pon::Float(3.0));
+    let ent = doc.append_entity(Some(root), "Entity", Some("tmp".to_string())).unwrap();
+    doc.set_property(&ent, "x", Pon::Float(7.0)).unwrap();
+    assert_eq!(doc.get_pending_properties().len(), 0);
+    assert_eq!(doc.get_property(&root, "y").unwrap().concretize().unwrap(), Pon::Float(7.0));
+    doc.remove_entity(&ent).unwrap();
+    assert_eq!(doc.dependencies_of(&PropRef::new(&root, "y")).unwrap(), &vec![PropRef::new(&root, "x")]);
+    assert_eq!(doc.get_property(&root, "y").unwrap().concretize().unwrap(), Pon::Float(3.0));
+}
+
+#[test]
+fn test_remove_entity_prunes_dependencies() {
+    let mut doc = Document::from_string(r#"<Entity name="root" y="@tmp.x ?? 1.0"><Entity name="tmp" x="5.0" /></Entity>"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    doc.remove_entity(&ent).unwrap();
+    assert_eq!(doc.dependencies_of(&PropRef::new(&root, "y")).unwrap().len(), 0);
+    assert_eq!(doc.find_cycles().len(), 0);
+    assert_eq!(doc.get_pending_properties(), vec![(PropRef::new(&root, "y"), DocError::CantFindEntityByName("tmp".to_string()))]);
+    assert_eq!(doc.get_property(&root, "y").unwrap().concretize().unwrap(), Pon::Float(1.0));
+    let ent = doc.append_entity(Some(root), "Entity", Some("tmp".to_string())).unwrap();
+    doc.set_property(&ent, "x", Pon::Float(7.0)).unwrap();
+    assert_eq!(doc.dependencies_of(&PropRef::new(&root, "y")).unwrap(), &vec![PropRef::new(&ent, "x")]);
+    assert_eq!(doc.get_property(&root, "y").unwrap().concretize().unwrap(), Pon::Float(7.0));
+}
+
+#[test]
+fn test_remove_entity_callback() {
+    let mut doc = Document::from_string(r#"<Entity><Entity name="tmp"><Entity name="child" /></Entity></Entity>"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    let child = doc.get_entity_by_name("child").unwrap();
+    let removed = Rc::new(RefCell::new(vec![]));
+    {
+        let removed = removed.clone();
+        doc.on_entity_removed = Some(Box::new(move |entity_id| {
+            removed.borrow_mut().push(*entity_id);
+        }));
+    }
+    doc.remove_entity(&ent).unwrap();
+    assert_eq!(*removed.borrow(), vec![ent, child]);
+}
+
+#[test]
+fn test_set_parent() {
+    let mut doc = Document::from_string(r#"<Entity><Entity name="a" x="1.0" /><Entity name="b" x="2.0"><Entity name="tmp" y="@parent.x" /></Entity></Entity>"#).unwrap();
+    let a = doc.get_entity_by_name("a").unwrap();
+    let b = doc.get_entity_by_name("b").unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    doc.set_parent(&ent, &a, None).unwrap();
+    assert_eq!(*doc.get_children(&a).unwrap(), vec![ent]);
+    assert_eq!(doc.get_children(&b).unwrap().len(), 0);
+    assert_eq!(doc.get_property(&ent, "y").unwrap().concretize().unwrap(), Pon::Float(1.0));
+}
+
+#[test]
+fn test_set_parent_only_rebinds_changed() {
+    let mut doc = Document::from_string(r#"<Entity name="root" x="0.0"><Entity name="a" x="1.0"><Entity name="tmp" y="@parent.x" /></Entity><Entity name="b" x="2.0" z="@parent.x" /></Entity>"#).unwrap();
+    let a = doc.get_entity_by_name("a").unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    let set = Rc::new(RefCell::new(vec![]));
+    {
+        let set = set.clone();
+        doc.on_property_set = Some(Box::new(move |entity_id, property_key| {
+            set.borrow_mut().push(PropRef::new(entity_id, property_key));
+        }));
+    }
+    let b = doc.get_entity_by_name("b").unwrap();
+    doc.set_parent(&ent, &b, None).unwrap();
+    assert_eq!(*set.borrow(), vec![PropRef::new(&ent, "y")]);
+    assert_eq!(doc.get_property_dependants(&a, "x").unwrap().len(), 0);
+    assert_eq!(doc.get_property(&ent, "y").unwrap().concretize().unwrap(), Pon::Float(2.0));
+}
+
+#[test]
+fn test_set_parent_rebind_failure() {
+    let mut doc = Document::from_string(r#"<Entity name="root"><Entity name="a"><Entity name="item" x="1.0" /><Entity name="tmp" y="@parent:item.x" z="@parent:item.x" /></Entity><Entity name="b" /></Entity>"#).unwrap();
+    let a = doc.get_entity_by_name("a").unwrap();
+    let b = doc.get_entity_by_name("b").unwrap();
+    let item = doc.get_entity_by_name("item").unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    match doc.set_parent(&ent, &b, None) {
+        Err(DocError::UnresolvedProperties(failures)) => {
+            let mut failed: Vec<String> = failures.into_iter().map(|(prop_ref, _)| prop_ref.property_key).collect();
+            failed.sort();
+            assert_eq!(failed, vec!["y", "z"]);
+        },
+        other => panic!("expected unresolved properties, got {:?}", other)
+    }
+    assert_eq!(*doc.get_children(&b).unwrap(), vec![ent]);
+    assert_eq!(doc.get_property(&ent, "y").err().unwrap(), DocError::NoSuchProperty("y".to_string()));
+    assert_eq!(doc.get_property_dependants(&item, "x").unwrap().len(), 0);
+    assert_eq!(doc.get_pending_properties().len(), 2);
+    doc.set_parent(&ent, &a, None).unwrap();
+    assert_eq!(doc.get_property(&ent, "y").unwrap().concretize().unwrap(), Pon::Float(1.0));
+    assert_eq!(doc.get_pending_properties().len(), 0);
+}
+
+#[test]
+fn test_set_parent_to_descendant() {
+    let mut doc = Document::from_string(r#"<Entity><Entity name="a"><Entity name="b" /></Entity></Entity>"#).unwrap();
+    let a = doc.get_entity_by_name("a").unwrap();
+    let b = doc.get_entity_by_name("b").unwrap();
+    assert_eq!(doc.set_parent(&a, &b, None).err().unwrap(), DocError::InvalidParent);
+    assert_eq!(doc.set_parent(&a, &a, None).err().unwrap(), DocError::InvalidParent);
+}
+
+#[test]
+fn test_insert_child_at() {
+    let mut doc = Document::from_string(r#"<Entity name="root"><Entity name="a" /><Entity name="b"><Entity name="c" /></Entity></Entity>"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    let a = doc.get_entity_by_name("a").unwrap();
+    let b = doc.get_entity_by_name("b").unwrap();
+    let c = doc.get_entity_by_name("c").unwrap();
+    doc.insert_child_at(&root, &c, 0).unwrap();
+    assert_eq!(*doc.get_children(&root).unwrap(), vec![c, a, b]);
+    assert_eq!(doc.insert_child_at(&root, &a, 5).err().unwrap(), DocError::InvalidChildIndex(5));
+}
+
+#[test]
+fn test_move_child_search() {
+    let mut doc = Document::from_string(r#"<Entity name="root"><Entity name="item" x="1.0" /><Entity name="item" x="2.0" /></Entity>"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    doc.set_property(&root, "y", Pon::from_string("@this:item.x").unwrap()).unwrap();
+    assert_eq!(doc.get_property(&root, "y").unwrap().concretize().unwrap(), Pon::Float(1.0));
+    doc.move_child(&root, 1, 0).unwrap();
+    assert_eq!(doc.get_property(&root, "y").unwrap().concretize().unwrap(), Pon::Float(2.0));
+}
+
+#[test]
+fn test_remove_property() {
+    let mut doc = Document::from_string(r#"<Entity name="tmp" x="5.0" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    doc.remove_property(&ent, "x").unwrap();
+    assert_eq!(doc.has_property(&ent, "x").unwrap(), false);
+    assert_eq!(doc.get_property(&ent, "x").err().unwrap(), DocError::NoSuchProperty("x".to_string()));
+    assert_eq!(doc.remove_property(&ent, "x").err().unwrap(), DocError::NoSuchProperty("x".to_string()));
+}
+
+#[test]
+fn test_remove_property_pending() {
+    let mut doc = Document::from_string(r#"<Entity name="root" y="@later.x" />"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    doc.remove_property(&root, "y").unwrap();
+    assert_eq!(doc.get_pending_properties().len(), 0);
+    let later = doc.append_entity(Some(root), "Entity", Some("later".to_string())).unwrap();
+    doc.set_property(&later, "x", Pon::Float(9.0)).unwrap();
+    assert_eq!(doc.has_property(&root, "y").unwrap(), false);
+    assert_eq!(doc.remove_property(&root, "y").err().unwrap(), DocError::NoSuchProperty("y".to_string()));
+}
+
+#[test]
+fn test_remove_property_dependants() {
+    let mut doc = Document::from_string(r#"<Entity name="tmp" x="5.0" y="@this.x" z="{ some: [@this.x] }" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    doc.remove_property(&ent, "x").unwrap();
+    let err = PonTranslateErr::ReferenceToNonExistentProperty(NamedPropRef::new(EntityPath::This, "x"));
+    assert_eq!(doc.get_property(&ent, "y").unwrap().concretize().err().unwrap(), err);
+    assert_eq!(doc.get_property(&ent, "z").unwrap().concretize().err().unwrap(), err);
+    doc.set_property(&ent, "x", Pon::Float(2.0)).unwrap();
+    assert_eq!(doc.get_property(&ent, "y").unwrap().concretize().unwrap(), Pon::Float(2.0));
+}
+
+#[test]
+fn test_property_reset_dependants() {
+    let mut doc = Document::from_string(r#"<Entity name="tmp" x="5.0" z="1.0" y="@this.x" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    for _ in 0..3 {
+        doc.set_property(&ent, "y", Pon::from_string("[@this.x, @this.x]").unwrap()).unwrap();
+    }
+    assert_eq!(*doc.get_property_dependants(&ent, "x").unwrap(), vec![PropRef::new(&ent, "y")]);
+    doc.set_property(&ent, "y", Pon::from_string("@this.z").unwrap()).unwrap();
+    assert_eq!(doc.get_property_dependants(&ent, "x").unwrap().len(), 0);
+    assert_eq!(*doc.get_property_dependants(&ent, "z").unwrap(), vec![PropRef::new(&ent, "y")]);
+}
+
+#[test]
+fn test_dependencies_of() {
+    let mut doc = Document::from_string(r#"<Entity name="tmp" x="5.0" y="{ a: @this.x, b: @this.z }" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    {
+        let deps = doc.dependencies_of(&PropRef::new(&ent, "y")).unwrap();
+        assert_eq!(deps.len(), 2);
+        assert!(deps.contains(&PropRef::new(&ent, "x")));
+        assert!(deps.contains(&PropRef::new(&ent, "z")));
+    }
+    doc.remove_property(&ent, "y").unwrap();
+    assert_eq!(doc.dependencies_of(&PropRef::new(&ent, "y")).unwrap().len(), 0);
+    assert_eq!(doc.get_property_dependants(&ent, "x").unwrap().len(), 0);
+}
+
+#[test]
+fn test_dependency_cycle() {
+    let mut doc = Document::from_string(r#"<Entity name="tmp" a="@this.b" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    assert_eq!(doc.set_property(&ent, "b", Pon::from_string("{ x: @this.a }").unwrap()).err().unwrap(),
+        DocError::DependencyCycle(vec![PropRef::new(&ent, "b"), PropRef::new(&ent, "a")]));
+    assert_eq!(doc.has_property(&ent, "b").unwrap(), false);
+    assert_eq!(doc.get_property_dependants(&ent, "a").unwrap().len(), 0);
+}
+
+#[test]
+fn test_dependency_cycle_self() {
+    let mut doc = Document::from_string(r#"<Entity name="tmp" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    assert_eq!(doc.set_property(&ent, "a", Pon::from_string("@this.a").unwrap()).err().unwrap(),
+        DocError::DependencyCycle(vec![PropRef::new(&ent, "a")]));
+}
+
+#[test]
+fn test_find_cycles() {
+    let mut doc = Document::from_string(r#"<Entity name="tmp" x="5.0" a="@this.x" b="@this.a" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    assert_eq!(doc.find_cycles().len(), 0);
+    doc.entities.get_mut(&ent).unwrap().get_or_create_property("x").dependencies.push(PropRef::new(&ent, "b"));
+    let cycles = doc.find_cycles();
+    assert_eq!(cycles.len(), 1);
+    assert_eq!(cycles[0].len(), 3);
+}
+
+#[test]
+fn test_property_reference_forward() {
+    let doc = Document::from_string(r#"<Entity name="root" y="@player.x"><Entity name="player" x="5.0" /></Entity>"#).unwrap();
+    let ent = doc.get_entity_by_name("root").unwrap();
+    assert_eq!(doc.get_property(&ent, "y").unwrap().concretize().unwrap(), Pon::Float(5.0));
+    assert_eq!(doc.get_pending_properties().len(), 0);
+}
+
+#[test]
+fn test_property_reference_forward_search() {
+    let doc = Document::from_string(r#"<Entity name="root" y="@this:player.x"><Entity><Entity name="player" x="5.0" /></Entity></Entity>"#).unwrap();
+    let ent = doc.get_entity_by_name("root").unwrap();
+    assert_eq!(doc.get_property(&ent, "y").unwrap().concretize().unwrap(), Pon::Float(5.0));
+}
+
+#[test]
+fn test_property_reference_pending_append() {
+    let mut doc = Document::from_string(r#"<Entity name="root" y="@later.x" />"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    assert_eq!(doc.get_pending_properties(), vec![(PropRef::new(&root, "y"), DocError::CantFindEntityByName("later".to_string()))]);
+    let later = doc.append_entity(Some(root), "Entity", Some("later".to_string())).unwrap();
+    doc.set_property(&later, "x", Pon::Float(9.0)).unwrap();
+    assert_eq!(doc.get_property(&root, "y").unwrap().concretize().unwrap(), Pon::Float(9.0));
+    assert_eq!(doc.get_pending_properties().len(), 0);
+}
+
+#[test]
+fn test_property_reference_pending_overridden() {
+    let mut doc = Document::from_string(r#"<Entity name="root" y="@later.x" />"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    doc.set_property(&root, "y", Pon::Float(1.0)).unwrap();
+    doc.append_entity(Some(root), "Entity", Some("later".to_string())).unwrap();
+    assert_eq!(*doc.get_property(&root, "y").unwrap(), Pon::Float(1.0));
+}
+
+#[test]
+fn test_load_report_parse_error() {
+    let (doc, report) = Document::from_string_with_report(r#"<Entity name="tmp" x="[5.0" y="1" />"#, &DocumentLoadOptions::default()).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    assert_eq!(*doc.get_property(&ent, "y").unwrap(), Pon::Integer(1));
+    assert_eq!(report.diagnostics.len(), 1);
+    let diagnostic = &report.diagnostics[0];
+    assert_eq!(diagnostic.kind, LoadDiagnosticKind::PropertyParseError);
+    assert_eq!(diagnostic.entity_type_name, "Entity".to_string());
+    assert_eq!(diagnostic.entity_name, Some("tmp".to_string()));
+    assert_eq!(diagnostic.attribute, Some("x".to_string()));
+    assert_eq!(diagnostic.text, "[5.0".to_string());
+    assert_eq!((diagnostic.line, diagnostic.column), (1, 1));
+}
+
+#[test]
+fn test_load_report_unresolved() {
+    let (_, report) = Document::from_string_with_report("<Entity>\n  <Entity name=\"tmp\" y=\"@what.x\" />\n</Entity>", &DocumentLoadOptions::default()).unwrap();
+    assert_eq!(report.diagnostics.len(), 1);
+    assert_eq!(report.diagnostics[0].kind, LoadDiagnosticKind::UnresolvedProperty);
+    assert_eq!(report.diagnostics[0].attribute, Some("y".to_string()));
+    assert_eq!((report.diagnostics[0].line, report.diagnostics[0].column), (2, 3));
+}
+
+#[test]
+fn test_load_report_strict() {
+    let options = DocumentLoadOptions { strict: true };
+    match Document::from_string_with_report(r#"<Entity name="tmp" x="[5.0" />"#, &options) {
+        Err(DocError::LoadDiagnostic(diagnostic)) => assert_eq!(diagnostic.kind, LoadDiagnosticKind::PropertyParseError),
+        _ => panic!("Expected strict load to fail")
+    }
+    assert!(Document::from_string_with_report(r#"<Entity name="tmp" x="5.0" />"#, &options).is_ok());
+}
+
+#[test]
+fn test_from_file_missing() {
+    let path = Path::new("this_file_does_not_exist.xml");
+    match Document::from_file(path) {
+        Err(DocError::Io(err_path, _)) => assert_eq!(err_path, path.to_path_buf()),
+        _ => panic!("Expected an io error")
+    }
+}
+
+#[test]
+fn test_from_string_bad_xml() {
+    match Document::from_string(r#"<Entity name="tmp"><Entity></Other></Entity>"#) {
+        Err(DocError::Xml(_)) => {},
+        _ => panic!("Expected an xml error")
+    }
+}
+
+#[test]
+fn test_document_write_name_first() {
+    let doc = Document::from_string(r#"<Entity name="tmp" x="5" a="1" />"#).unwrap();
+    let mut buff = vec![];
+    doc.write_to(&mut buff, &DocumentWriteOptions::pretty()).unwrap();
+    let xml = String::from_utf8(buff).unwrap();
+    assert!(xml.starts_with("<?xml version=\"1.0\""));
+    assert!(xml.contains(r#"<Entity name="tmp" a="1" x="5""#));
+}
+
+#[test]
+fn test_document_write_unwrap_dependencies() {
+    let doc = Document::from_string(r#"<Entity name="tmp" x="5" y="@this.x" />"#).unwrap();
+    let mut options = DocumentWriteOptions::default();
+    let mut buff = vec![];
+    doc.write_to(&mut buff, &options).unwrap();
+    assert!(String::from_utf8(buff).unwrap().contains(r#"y="@this.x""#));
+    options.unwrap_dependencies = true;
+    let mut buff = vec![];
+    doc.write_to(&mut buff, &options).unwrap();
+    assert!(String::from_utf8(buff).unwrap().contains(r#"y="5""#));
+}
+
+#[test]
+fn test_document_save() {
+    let doc = Document::from_string(r#"<Entity name="root" x="5"><Entity name="tmp" y="@parent.x" /></Entity>"#).unwrap();
+    let path = ::std::env::temp_dir().join("pyramid_test_document_save.xml");
+    doc.save(&path, &DocumentWriteOptions::pretty()).unwrap();
+    let loaded = Document::from_file(&path).unwrap();
+    let ent = loaded.get_entity_by_name("tmp").unwrap();
+    assert_eq!(loaded.get_property(&ent, "y").unwrap().concretize().unwrap(), Pon::Integer(5));
+}
+
+#[test]
+fn test_property_expression() {
+    let mut doc = Document::from_string(r#"<Entity name="tmp" x="5.0" y="@this.x * 2 + 1" visible="@this.x > 3.0" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    assert_eq!(doc.get_property(&ent, "y").unwrap().concretize().unwrap(), Pon::Float(11.0));
+    assert_eq!(doc.get_property(&ent, "visible").unwrap().concretize().unwrap(), Pon::Boolean(true));
+    assert_eq!(doc.get_property_dependants(&ent, "x").unwrap().len(), 2);
+    doc.set_property(&ent, "x", Pon::Float(1.0)).unwrap();
+    assert_eq!(doc.get_property(&ent, "y").unwrap().concretize().unwrap(), Pon::Float(3.0));
+    assert_eq!(doc.get_property(&ent, "visible").unwrap().concretize().unwrap(), Pon::Boolean(false));
+}
+
+#[test]
+fn test_property_fallback() {
+    let mut doc = Document::from_string(r#"<Entity name="tmp" y="@this.override ?? 5.0" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    assert_eq!(doc.get_property(&ent, "y").unwrap().concretize().unwrap(), Pon::Float(5.0));
+    doc.set_property(&ent, "override", Pon::Float(2.0)).unwrap();
+    assert_eq!(doc.get_property(&ent, "y").unwrap().concretize().unwrap(), Pon::Float(2.0));
+    assert_eq!(doc.get_property_dependants(&ent, "override").unwrap().len(), 1);
+}
+
+#[test]
+fn test_property_template() {
+    let mut doc = Document::from_string(r#"<Entity name="game" score="5"><Entity name="label" text="`Score: ${@game.score}`" /></Entity>"#).unwrap();
+    let game = doc.get_entity_by_name("game").unwrap();
+    let label = doc.get_entity_by_name("label").unwrap();
+    assert_eq!(doc.get_property(&label, "text").unwrap().concretize().unwrap(), Pon::String("Score: 5".to_string()));
+    assert_eq!(doc.get_property_dependants(&game, "score").unwrap(), &vec![PropRef::new(&label, "text")]);
+    doc.set_property(&game, "score", Pon::Integer(7)).unwrap();
+    assert_eq!(doc.get_property(&label, "text").unwrap().concretize().unwrap(), Pon::String("Score: 7".to_string()));
+}
+
+#[test]
+fn test_snapshot_report() {
+    let snapshot = DocumentSnapshot { root: Some(EntitySnapshot {
+        type_name: "Entity".to_string(),
+        name: Some("tmp".to_string()),
+        properties: vec![("y".to_string(), Pon::from_string("@what.x").unwrap())],
+        children: vec![]
+    }) };
+    let (_, report) = Document::from_snapshot_with_report(&snapshot, &DocumentLoadOptions::default()).unwrap();
+    assert_eq!(report.diagnostics.len(), 1);
+    assert_eq!(report.diagnostics[0].kind, LoadDiagnosticKind::UnresolvedProperty);
+    assert_eq!(report.diagnostics[0].entity_name, Some("tmp".to_string()));
+    assert_eq!(report.diagnostics[0].attribute, Some("y".to_string()));
+    assert_eq!(report.diagnostics[0].text, "@what.x".to_string());
+    match Document::from_snapshot_with_report(&snapshot, &DocumentLoadOptions { strict: true }) {
+        Err(DocError::LoadDiagnostic(diagnostic)) => assert_eq!(diagnostic.kind, LoadDiagnosticKind::UnresolvedProperty),
+        _ => panic!("Expected strict load to fail")
+    }
+}
+
+#[test]
+fn test_snapshot_report_set_property_failed() {
+    let snapshot = DocumentSnapshot { root: Some(EntitySnapshot {
+        type_name: "Entity".to_string(),
+        name: Some("tmp".to_string()),
+        properties: vec![("a".to_string(), Pon::from_string("@this.a").unwrap()), ("b".to_string(), Pon::Integer(1))],
+        children: vec![EntitySnapshot { type_name: "Entity".to_string(), name: None, properties: vec![], children: vec![] }]
+    }) };
+    let (doc, report) = Document::from_snapshot_with_report(&snapshot, &DocumentLoadOptions::default()).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    assert_eq!(*doc.get_property(&ent, "b").unwrap(), Pon::Integer(1));
+    assert_eq!(doc.get_children(&ent).unwrap().len(), 1);
+    assert_eq!(report.diagnostics.len(), 1);
+    assert_eq!(report.diagnostics[0].kind, LoadDiagnosticKind::SetPropertyFailed);
+    assert_eq!(report.diagnostics[0].attribute, Some("a".to_string()));
+}
+
+#[test]
+fn test_snapshot_round_trip() {
+    let doc = Document::from_string(r#"<Entity name="root" x="5.0"><Entity name="child" y="@root.x * 2" /><Entity /></Entity>"#).unwrap();
+    let snapshot = doc.snapshot();
+    let root = snapshot.root.as_ref().unwrap();
+    assert_eq!(root.name, Some("root".to_string()));
+    assert_eq!(root.properties, vec![("x".to_string(), Pon::Float(5.0))]);
+    assert_eq!(root.children[0].properties, vec![("y".to_string(), Pon::from_string("@root.x * 2").unwrap())]);
+    assert_eq!(root.children[1].name, None);
+    let copy = Document::from_snapshot(&snapshot).unwrap();
+    assert_eq!(copy.snapshot(), snapshot);
+    let child = copy.get_entity_by_name("child").unwrap();
+    assert_eq!(copy.get_property(&child, "y").unwrap().concretize().unwrap(), Pon::Float(10.0));
+}
+
+#[test]
+fn test_scene_load() {
+    let doc = Document::from_scene_string(r#"
+        // comments are fine too
+        Entity name=tmp {
+            x: 5.0,
+            children: [
+                Sprite name='the sprite' { y: @tmp.x * 2 },
+                Sprite {}
+            ]
+        }"#).unwrap();
+    let tmp = doc.get_entity_by_name("tmp").unwrap();
+    let sprite = doc.get_entity_by_name("the sprite").unwrap();
+    assert_eq!(doc.get_entity_type_name(&sprite).unwrap(), "Sprite");
+    assert_eq!(doc.get_children(&tmp).unwrap().len(), 2);
+    assert_eq!(doc.get_property(&sprite, "y").unwrap().concretize().unwrap(), Pon::Float(10.0));
+}
+
+#[test]
+fn test_scene_write() {
+    let doc = Document::from_string(r#"<Entity name="tmp" x="5.0" children="[1]"><Sprite y="@tmp.x" /><Entity /></Entity>"#).unwrap();
+    assert_eq!(doc.to_scene_string(), r#"Entity name=tmp {
+  'children': [1],
+  x: 5.0,
+  children: [
+    Sprite {
+      y: @tmp.x
+    },
+    Entity {}
+  ]
+}
+"#);
+    assert_eq!(doc.to_scene_string_with(&PonStringifyOptions::compact()), "Entity name=tmp {'children':[1],x:5.0,children:[Sprite {y:@tmp.x},Entity {}]}\n");
+}
+
+#[test]
+fn test_scene_round_trip() {
+    let doc = Document::from_string(r#"<Entity name="root" x="5.0" mesh="static_mesh { vertices: #f32[0.0, 1.0, 2.0] }"><Entity name="child" y="@root.x * 2" /><Entity /></Entity>"#).unwrap();
+    let copy = Document::from_scene_string(&doc.to_scene_string()).unwrap();
+    assert_eq!(copy.snapshot(), doc.snapshot());
+    assert_eq!(Document::from_scene_string("").unwrap().get_root(), None);
+}
+
+#[test]
+fn test_scene_report() {
+    let (_, report) = Document::from_scene_string_with_report("Entity {\n  children: [\n    Entity { y: @what.x }\n  ]\n}", &DocumentLoadOptions::default()).unwrap();
+    assert_eq!(report.diagnostics.len(), 1);
+    assert_eq!(report.diagnostics[0].kind, LoadDiagnosticKind::UnresolvedProperty);
+    assert_eq!((report.diagnostics[0].line, report.diagnostics[0].column), (3, 14));
+    let (_, report) = Document::from_scene_string_with_report("Entity {\n  x: 1,\n  // comment\n  y: @what.x\n}", &DocumentLoadOptions::default()).unwrap();
+    assert_eq!((report.diagnostics[0].line, report.diagnostics[0].column), (4, 3));
+    let (doc, report) = Document::from_scene_string_with_report("Entity name=tmp {\n  a: @this.a,\n  b: 1\n}", &DocumentLoadOptions::default()).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    assert_eq!(*doc.get_property(&ent, "b").unwrap(), Pon::Integer(1));
+    assert_eq!(report.diagnostics.len(), 1);
+    assert_eq!(report.diagnostics[0].kind, LoadDiagnosticKind::SetPropertyFailed);
+    assert_eq!((report.diagnostics[0].line, report.diagnostics[0].column), (2, 3));
+    match Document::from_scene_string("Entity { x: }") {
+        Err(DocError::ParseError(err)) => assert_eq!((err.line, err.column), (1, 13)),
+        other => panic!("expected a parse error, got {:?}", other.err())
+    }
+}
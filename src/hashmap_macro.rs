@@ -3,7 +3,7 @@
 macro_rules! hashmap(
     { $($key:expr => $value:expr),+ } => {
         {
-            let mut m = ::std::collections::HashMap::new();
+            let mut m = $crate::pon::PonObject::new();
             $(
                 m.insert(::std::convert::From::from($key), $value);
             )+
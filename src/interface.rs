@@ -14,6 +14,8 @@ pub trait ISubSystem {
         let prop_refs: Vec<PropRef> = { system.document().get_properties(&entity_id).unwrap() };
         self.on_property_value_change(system, &prop_refs);
     }
+    // Called after the entity has been taken out of the document, so only the id is available
+    fn on_entity_removed(&mut self, system: &mut System, entity_id: &EntityId) {}
     fn on_property_value_change(&mut self, system: &mut System, prop_refs: &Vec<PropRef>) {}
     fn update(&mut self, system: &mut System) {}
 }
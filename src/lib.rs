@@ -3,15 +3,30 @@
 
 extern crate xml;
 extern crate cgmath;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(feature = "json")]
+extern crate serde_json;
 
 #[macro_use]
 pub mod hashmap_macro;
 pub mod document;
 #[cfg(test)]
 mod pon_test;
+#[cfg(test)]
+mod pon_roundtrip_test;
 #[macro_use]
 pub mod pon;
 pub mod pon_translations;
+pub mod pon_expression;
+pub mod pon_functions;
+pub mod pon_object;
+pub mod pon_binary;
+#[cfg(feature = "serde")]
+pub mod pon_serde;
+#[cfg(feature = "json")]
+pub mod pon_json;
 pub mod system;
 pub mod interface;
 pub mod pon_to_cgmath;
@@ -1,11 +1,11 @@
 peg_file! pon_peg("pon.rustpeg");
 
-pub use pon::pon_peg::ParseError as PonParseError;
-
 use document::EntityId;
 pub use pon_translations::*;
+pub use pon_expression::*;
+pub use pon_functions::*;
+pub use pon_object::*;
 
-use std::collections::HashMap;
 use std::slice::SliceConcatExt;
 use std::hash::Hasher;
 use std::hash::Hash;
@@ -16,6 +16,41 @@ use std::ops::Deref;
 use std::rc::Rc;
 use std::cell::{RefCell,Ref};
 
+#[derive(PartialEq, Debug, Clone)]
+pub struct PonParseError {
+    // line and column are 1-based, offset is in bytes from the start of the source
+    pub line: usize,
+    pub column: usize,
+    pub offset: usize,
+    pub expected: Vec<String>,
+    pub source_line: String
+}
+impl PonParseError {
+    fn from_peg_error(err: pon_peg::ParseError, source: &str) -> PonParseError {
+        let mut expected: Vec<String> = err.expected.iter().map(|x| x.to_string()).collect();
+        expected.sort();
+        PonParseError {
+            line: err.line,
+            column: err.column,
+            offset: err.offset,
+            expected: expected,
+            source_line: source.lines().nth(err.line - 1).unwrap_or("").to_string()
+        }
+    }
+    // The offending line with a caret under the error position
+    pub fn snippet(&self) -> String {
+        // keep tabs so the caret lines up with the line above
+        let indent: String = self.source_line.chars().take(self.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+        format!("{}\n{}^", self.source_line, indent)
+    }
+}
+impl ToString for PonParseError {
+    fn to_string(&self) -> String {
+        format!("Parse error at line {}, column {}: expected one of {}\n{}", self.line, self.column, self.expected.join(", "), self.snippet())
+    }
+}
+
 #[derive(PartialEq, Eq, Debug, Clone, Hash, PartialOrd, Ord)]
 pub enum EntityPath {
     This,
@@ -23,6 +58,21 @@ pub enum EntityPath {
     Named(String),
     Search(Box<EntityPath>, String)
 }
+impl EntityPath {
+    pub fn from_string(string: &str) -> Result<EntityPath, PonParseError> {
+        match pon_peg::entity_path(string) {
+            Ok(path) => Ok(path),
+            Err(err) => Err(PonParseError::from_peg_error(err, string))
+        }
+    }
+    // Whether what the path points at can change when entities are moved around in the tree
+    pub fn is_tree_relative(&self) -> bool {
+        match self {
+            &EntityPath::Parent | &EntityPath::Search(..) => true,
+            _ => false
+        }
+    }
+}
 impl ToString for EntityPath {
     fn to_string(&self) -> String {
         match self {
@@ -46,6 +96,12 @@ impl NamedPropRef {
             property_key: property_key.to_string()
         }
     }
+    pub fn from_string(string: &str) -> Result<NamedPropRef, PonParseError> {
+        match pon_peg::named_prop_ref(string) {
+            Ok(named_prop_ref) => Ok(named_prop_ref),
+            Err(err) => Err(PonParseError::from_peg_error(err, string))
+        }
+    }
 }
 impl ToString for NamedPropRef {
     fn to_string(&self) -> String {
@@ -70,17 +126,103 @@ impl Eq for PropRef {
     // hack, relies on PartialEq to be defined
 }
 
+// An entity as written in a pon scene file, e.g. `Entity name=tmp { x: 5.0, children: [...] }`.
+// offset is where the entity starts in the source, in bytes, and each property has the offset of
+// its key. Loaded with Document::from_scene_string.
+#[derive(PartialEq, Debug, Clone)]
+pub struct SceneEntity {
+    pub type_name: String,
+    pub name: Option<String>,
+    pub properties: Vec<(String, Pon, usize)>,
+    pub children: Vec<SceneEntity>,
+    pub offset: usize
+}
+impl SceneEntity {
+    // None if the source has no entity at all, like the scene written for an empty document
+    pub fn from_string(string: &str) -> Result<Option<SceneEntity>, PonParseError> {
+        match pon_peg::scene(string) {
+            Ok(scene) => Ok(scene),
+            Err(err) => Err(PonParseError::from_peg_error(err, string))
+        }
+    }
+    // Writes the entity the way from_string reads it. The offsets aren't used.
+    pub fn to_string_with(&self, options: &PonStringifyOptions) -> String {
+        self.stringify(options, 0)
+    }
+    fn stringify(&self, options: &PonStringifyOptions, level: usize) -> String {
+        let mut header = stringify_key(&self.type_name);
+        if let Some(ref name) = self.name {
+            header = format!("{} name={}", header, stringify_key(name));
+        }
+        let separator = if options.compact { ":" } else { ": " };
+        let mut items: Vec<String> = self.properties.iter().map(|&(ref key, ref value, _)| {
+            // children unquoted is the list of child entities
+            let key = if key == "children" { "'children'".to_string() } else { stringify_key(key) };
+            let prefix = format!("{}{}", key, separator);
+            format!("{}{}", prefix, value.stringify(options, level + 1, prefix.chars().count()))
+        }).collect();
+        if self.children.len() > 0 {
+            let children = self.children.iter().map(|child| child.stringify(options, level + 2)).collect();
+            items.push(format!("children{}{}", separator, scene_block("[", "]", children, options, level + 1)));
+        }
+        format!("{} {}", header, scene_block("{", "}", items, options, level))
+    }
+}
+
+// One item per line unless compact. Unlike pon arrays and objects, entities aren't put on a single
+// line when they would fit, which keeps the tree readable.
+fn scene_block(open: &str, close: &str, items: Vec<String>, options: &PonStringifyOptions, level: usize) -> String {
+    if items.len() == 0 {
+        return format!("{}{}", open, close);
+    }
+    if options.compact {
+        return format!("{}{}{}", open, items.join(","), close);
+    }
+    let indent: String = (0..options.indent * (level + 1)).map(|_| ' ').collect();
+    let mut s = format!("{}\n", open);
+    for (i, item) in items.iter().enumerate() {
+        s.push_str(&indent);
+        s.push_str(item);
+        if i + 1 < items.len() || options.trailing_commas {
+            s.push(',');
+        }
+        s.push('\n');
+    }
+    s.push_str(&indent[..options.indent * level]);
+    s.push_str(close);
+    s
+}
+
+// Used by the grammar, an entry in the braces of a scene entity
+pub enum SceneItem {
+    Property(String, Pon, usize),
+    Children(Vec<SceneEntity>)
+}
+
 #[derive(PartialEq, Debug, Clone)]
 pub struct TypedPon {
     pub type_name: String,
     pub data: Pon
 }
 impl TypedPon {
-    fn stringify(&self, options: &PonStringifyOptions) -> String {
-        format!("{} {}", self.type_name.to_string(), self.data.to_string())
+    fn stringify(&self, options: &PonStringifyOptions, level: usize, column: usize) -> String {
+        if self.data.needs_parentheses() {
+            let column = column + self.type_name.chars().count() + 2;
+            format!("{} ({})", self.type_name.to_string(), self.data.stringify(options, level, column))
+        } else {
+            let column = column + self.type_name.chars().count() + 1;
+            format!("{} {}", self.type_name.to_string(), self.data.stringify(options, level, column))
+        }
     }
 }
 
+// if, then and else are keywords inside an expression, but can still be used as type names or in
+// references. They can't start the data of a typed value though, which is what tells `a then b`
+// apart from a typed value a with data `then b`.
+pub fn is_keyword(name: &str) -> bool {
+    name == "if" || name == "then" || name == "else"
+}
+
 #[derive(PartialEq, Debug, Clone)]
 pub struct ResolvedDependency {
     pub prop_ref: PropRef,
@@ -95,7 +237,7 @@ pub enum Pon {
     Array(Vec<Pon>),
     FloatArray(Vec<f32>),
     IntegerArray(Vec<i64>),
-    Object(HashMap<String, Pon>),
+    Object(PonObject),
     Float(f32),
     Integer(i64),
     String(String),
@@ -103,17 +245,30 @@ pub enum Pon {
     Vector3(cgmath::Vector3<f32>),
     Vector4(cgmath::Vector4<f32>),
     Matrix4(cgmath::Matrix4<f32>),
+    Expression(Box<PonExpression>),
     Nil
 }
 
 
 impl Pon {
     pub fn from_string(string: &str) -> Result<Pon, PonParseError> {
-        pon_peg::body(string)
+        match pon_peg::body(string) {
+            Ok(pon) => Ok(pon),
+            Err(err) => Err(PonParseError::from_peg_error(err, string))
+        }
     }
     pub fn new_typed_pon(type_name: &str, data: Pon) -> Pon {
         Pon::TypedPon(Box::new(TypedPon { type_name: type_name.to_string(), data: data }))
     }
+    // Whether the value has to be put in parentheses when it's an operand or the data of a typed
+    // value: expressions, and typed values whose type name is a keyword
+    pub fn needs_parentheses(&self) -> bool {
+        match self {
+            &Pon::Expression(..) => true,
+            &Pon::TypedPon(box TypedPon { ref type_name, .. }) => is_keyword(type_name),
+            _ => false
+        }
+    }
     pub fn get_dependency_references(&self, references: &mut Vec<NamedPropRef>) {
         match self {
             &Pon::TypedPon(box TypedPon { ref data, .. } ) =>
@@ -131,6 +286,52 @@ impl Pon {
                     v.get_dependency_references(references);
                 }
             },
+            &Pon::Expression(box ref expression) => {
+                for v in expression.operands() {
+                    v.get_dependency_references(references);
+                }
+            },
+            _ => {}
+        }
+    }
+    // Like get_dependency_references, except that an if only contributes its condition and the
+    // branch it currently takes, since changes to the other branch can't change its value. Meant
+    // for resolved values, where the condition can be evaluated; when it can't, both branches
+    // count. The document still records every reference of both branches, so that a dependency
+    // cycle through either branch is refused when the property is set.
+    pub fn get_active_dependency_references(&self, references: &mut Vec<NamedPropRef>) {
+        match self {
+            &Pon::Expression(box PonExpression::Conditional(ref condition, ref a, ref b)) => {
+                condition.get_active_dependency_references(references);
+                match condition.concretize() {
+                    Ok(Pon::Boolean(true)) => a.get_active_dependency_references(references),
+                    Ok(Pon::Boolean(false)) => b.get_active_dependency_references(references),
+                    _ => {
+                        a.get_active_dependency_references(references);
+                        b.get_active_dependency_references(references);
+                    }
+                }
+            },
+            &Pon::Expression(box ref expression) => {
+                for v in expression.operands() {
+                    v.get_active_dependency_references(references);
+                }
+            },
+            &Pon::TypedPon(box TypedPon { ref data, .. } ) =>
+                data.get_active_dependency_references(references),
+            &Pon::DependencyReference(ref reference, _) => {
+                references.push(reference.clone());
+            },
+            &Pon::Object(ref hm) => {
+                for (_, v) in hm {
+                    v.get_active_dependency_references(references);
+                }
+            },
+            &Pon::Array(ref arr) => {
+                for v in arr {
+                    v.get_active_dependency_references(references);
+                }
+            },
             _ => {}
         }
     }
@@ -141,8 +342,10 @@ impl Pon {
                     &Some(ref pon) => pon.translate(context),
                     &None => return Err(PonTranslateErr::ReferenceToNonExistentProperty(named_prop_ref.clone()))
                 },
-                &None => panic!("Trying to translate on non-resolved dependency reference")
+                // Kept unresolved while its entity doesn't exist yet, see Document::set_property_or_defer
+                &None => Err(PonTranslateErr::ReferenceToNonExistentProperty(named_prop_ref.clone()))
             },
+            &Pon::Expression(box ref expression) => try!(expression.evaluate()).translate(context),
             _ => match self.inner_translate(context) {
                 Ok(val) => Ok(val),
                 Err(err) => {
@@ -183,12 +386,20 @@ impl Pon {
                        type_name: type_name.clone(),
                        data: try!(data.concretize())
                    }))),
-               &Pon::Object(ref hm) => Ok(Pon::Object(hm.iter().map(|(k,v)| {
-                       (k.clone(), v.concretize().unwrap())
-                   }).collect())),
-               &Pon::Array(ref arr) => Ok(Pon::Array(arr.iter().map(|v| {
-                        v.concretize().unwrap()
-                   }).collect())),
+               &Pon::Object(ref hm) => {
+                   let mut out = PonObject::new();
+                   for (k, v) in hm {
+                       out.insert(k.clone(), try!(v.concretize()));
+                   }
+                   Ok(Pon::Object(out))
+               },
+               &Pon::Array(ref arr) => {
+                   let mut out = vec![];
+                   for v in arr {
+                       out.push(try!(v.concretize()));
+                   }
+                   Ok(Pon::Array(out))
+               },
                _ => Ok(pon.clone())
            }
         })
@@ -202,7 +413,8 @@ impl Pon {
                     &None => return Err(PonTranslateErr::ReferenceToNonExistentProperty(named_prop_ref.clone()))
                 }
             },
-            &Pon::DependencyReference(_, None) => panic!("Cannot treat non-resolved pon as resolved."),
+            &Pon::DependencyReference(ref named_prop_ref, None) => Err(PonTranslateErr::ReferenceToNonExistentProperty(named_prop_ref.clone())),
+            &Pon::Expression(box ref expression) => try!(expression.evaluate()).as_resolved(func),
             _ => func(self),
         }
     }
@@ -219,7 +431,7 @@ impl Pon {
             _ => Err(PonTranslateErr::MismatchType { expected: "Array".to_string(), found: format!("{:?}", pon) })
         })
     }
-    pub fn as_object<'a, T: 'static, F: FnOnce(&HashMap<String, Pon>) -> Result<T, PonTranslateErr> + 'a>(&'a self, func: F) -> Result<T, PonTranslateErr> {
+    pub fn as_object<'a, T: 'static, F: FnOnce(&PonObject) -> Result<T, PonTranslateErr> + 'a>(&'a self, func: F) -> Result<T, PonTranslateErr> {
         self.as_resolved(|pon| match pon {
             &Pon::Object(ref value) => func(value),
             _ => Err(PonTranslateErr::MismatchType { expected: "Object".to_string(), found: format!("{:?}", pon) })
@@ -256,15 +468,21 @@ impl Pon {
         }
     }
 
-    fn stringify(&self, options: &PonStringifyOptions) -> String {
+    pub fn to_string_with(&self, options: &PonStringifyOptions) -> String {
+        self.stringify(options, 0, 0)
+    }
+
+    // level is how deeply nested the value is, for indenting the lines it's broken into, and column
+    // how many characters come before the value on its line, not counting the indentation
+    fn stringify(&self, options: &PonStringifyOptions, level: usize, column: usize) -> String {
         match self {
-            &Pon::TypedPon(box ref typed_pon) => typed_pon.stringify(&options),
+            &Pon::TypedPon(box ref typed_pon) => typed_pon.stringify(options, level, column),
             &Pon::DependencyReference(ref named_prop_ref, ref resolved) => {
                 if options.unwrap_dependencies {
                     match resolved {
                         &Some(ref resolved) => {
                             match &*resolved.value.borrow() {
-                                &Some(ref pon) => pon.stringify(&options),
+                                &Some(ref pon) => pon.stringify(options, level, column),
                                 &None => "()".to_string()
                             }
                         },
@@ -278,45 +496,312 @@ impl Pon {
             },
             &Pon::Reference(ref named_prop_ref) => format!("{}", named_prop_ref.to_string()),
             &Pon::Array(ref array) => {
-                let a: Vec<String> = array.iter().map(|x| x.stringify(&options)).collect();
-                let mut s = a.join(", ");
-                if s.len() > 120 { s = a.join(",\n"); }
-                format!("[{}]", s)
+                let items = array.iter().map(|x| x.stringify(options, level + 1, 0)).collect();
+                layout_list("[", "]", items, options, level, column)
             },
-            &Pon::FloatArray(ref array) => array.to_pon().stringify(&options),
-            &Pon::IntegerArray(ref array) => array.to_pon().stringify(&options),
+            &Pon::FloatArray(ref array) => stringify_typed_array("f32", array.iter().map(|v| stringify_float(*v, options)).collect(), options, level, column),
+            &Pon::IntegerArray(ref array) => stringify_typed_array("i64", array.iter().map(|v| v.to_string()).collect(), options, level, column),
             &Pon::Object(ref hm) => {
-                let a: Vec<String> = hm.iter().map(|(k, v)| format!("{}: {}", k.to_string(), v.stringify(&options))).collect();
-                let mut s = a.join(", ");
-                if s.len() > 120 { s = a.join(",\n"); }
-                format!("{{ {} }}", s)
+                let mut entries: Vec<(&String, &Pon)> = hm.iter().collect();
+                if options.sort_keys {
+                    entries.sort_by(|a, b| a.0.cmp(b.0));
+                }
+                let separator = if options.compact { ":" } else { ": " };
+                let items = entries.into_iter().map(|(k, v)| {
+                    let prefix = format!("{}{}", stringify_key(k), separator);
+                    let item = format!("{}{}", prefix, v.stringify(options, level + 1, prefix.chars().count()));
+                    if options.keep_comments && !options.compact && hm.comments(k).len() > 0 {
+                        // each comment on a line of its own, which also forces the object onto several lines
+                        let indent: String = (0..options.indent * (level + 1)).map(|_| ' ').collect();
+                        let mut s = String::new();
+                        for comment in hm.comments(k) {
+                            s.push_str(comment);
+                            s.push('\n');
+                            s.push_str(&indent);
+                        }
+                        s.push_str(&item);
+                        s
+                    } else {
+                        item
+                    }
+                }).collect();
+                if options.compact {
+                    layout_list("{", "}", items, options, level, column)
+                } else {
+                    layout_list("{ ", " }", items, options, level, column)
+                }
             },
-            &Pon::Float(ref v) => format!("{:.10}", v),
+            &Pon::Float(ref v) => stringify_float(*v, options),
             &Pon::Integer(ref v) => v.to_string(),
-            &Pon::String(ref v) => format!("'{}'", v),
+            &Pon::String(ref v) => stringify_string(v),
             &Pon::Boolean(ref v) => format!("{}", v),
-            &Pon::Vector3(ref v) => v.to_pon().stringify(&options),
-            &Pon::Vector4(ref v) => v.to_pon().stringify(&options),
-            &Pon::Matrix4(ref v) => v.to_pon().stringify(&options),
+            &Pon::Vector3(ref v) => stringify_typed_array("vec3", vec![v.x, v.y, v.z].into_iter().map(|v| stringify_float(v, options)).collect(), options, level, column),
+            &Pon::Vector4(ref v) => stringify_typed_array("vec4", vec![v.x, v.y, v.z, v.w].into_iter().map(|v| stringify_float(v, options)).collect(), options, level, column),
+            &Pon::Matrix4(ref v) => stringify_typed_array("mat4", vec![
+                    v.x.x, v.x.y, v.x.z, v.x.w,
+                    v.y.x, v.y.y, v.y.z, v.y.w,
+                    v.z.x, v.z.y, v.z.z, v.z.w,
+                    v.w.x, v.w.y, v.w.z, v.w.w
+                ].into_iter().map(|v| stringify_float(v, options)).collect(), options, level, column),
+            &Pon::Expression(box ref expression) => stringify_expression(expression, options, level, column),
             &Pon::Nil => "()".to_string()
         }
     }
 }
 
+fn stringify_expression(expression: &PonExpression, options: &PonStringifyOptions, level: usize, column: usize) -> String {
+    match expression {
+        &PonExpression::Binary(op, ref left, ref right) =>
+            stringify_infix(left, op.symbol(), right, options, level, column),
+        &PonExpression::Unary(UnaryOperator::Neg, ref operand) => match operand {
+            // -5 on its own would be read back as a negative literal
+            &Pon::Float(_) | &Pon::Integer(_) => format!("-({})", operand.stringify(options, level, column + 2)),
+            _ => format!("-{}", stringify_operand(operand, options, level, column + 1))
+        },
+        &PonExpression::Conditional(ref condition, ref a, ref b) => {
+            let mut s = "if ".to_string();
+            for (operand, keyword) in vec![(condition, " then "), (a, " else "), (b, "")] {
+                let column = next_column(column, &s, options, level);
+                s.push_str(&stringify_operand(operand, options, level, column));
+                s.push_str(keyword);
+            }
+            s
+        },
+        &PonExpression::Fallback(ref left, ref right) =>
+            stringify_infix(left, "??", right, options, level, column),
+        &PonExpression::Template(ref parts) => {
+            let mut s = "`".to_string();
+            for part in parts {
+                match part {
+                    &TemplatePart::Text(ref text) => s.push_str(&escape_string(text, &['`', '$'])),
+                    &TemplatePart::Value(ref v) => {
+                        let column = next_column(column, &s, options, level) + 2;
+                        s.push_str(&format!("${{{}}}", v.stringify(options, level, column)))
+                    }
+                }
+            }
+            s.push('`');
+            s
+        },
+        &PonExpression::Call(ref name, ref args) => {
+            let mut s = format!("{}(", name);
+            for (i, v) in args.iter().enumerate() {
+                if i > 0 {
+                    s.push_str(if options.compact { "," } else { ", " });
+                }
+                let column = next_column(column, &s, options, level);
+                s.push_str(&v.stringify(options, level, column));
+            }
+            s.push(')');
+            s
+        }
+    }
+}
+
+// Compact output leaves out the spaces around the operator, except after inf and nan which would
+// otherwise be read as the type name of a typed value, as in `inf-1`
+fn stringify_infix(left: &Pon, symbol: &str, right: &Pon, options: &PonStringifyOptions, level: usize, column: usize) -> String {
+    let mut s = stringify_operand(left, options, level, column);
+    if options.compact && !s.ends_with("inf") && !s.ends_with("nan") {
+        s.push_str(symbol);
+    } else {
+        s.push_str(&format!(" {} ", symbol));
+    }
+    let column = next_column(column, &s, options, level);
+    s.push_str(&stringify_operand(right, options, level, column));
+    s
+}
+
+fn stringify_operand(operand: &Pon, options: &PonStringifyOptions, level: usize, column: usize) -> String {
+    if operand.needs_parentheses() {
+        format!("({})", operand.stringify(options, level, column + 1))
+    } else {
+        operand.stringify(options, level, column)
+    }
+}
+
+// The column after `s`, when `s` was written starting at `column`. If it was broken over several
+// lines, that's the width of its last line past the indentation.
+fn next_column(column: usize, s: &str, options: &PonStringifyOptions, level: usize) -> usize {
+    match s.rfind('\n') {
+        Some(i) => s[i + 1..].chars().count().saturating_sub(level * options.indent),
+        None => column + s.chars().count()
+    }
+}
+
+// Turns `a + b - c` into ((a + b) - c)
+fn fold_binary(first: Pon, rest: Vec<(BinaryOperator, Pon)>) -> Pon {
+    rest.into_iter().fold(first, |left, (op, right)| Pon::Expression(Box::new(PonExpression::Binary(op, left, right))))
+}
+
+// With FloatPrecision::Shortest this is the shortest representation that parses back to exactly
+// the same value
+fn stringify_float(v: f32, options: &PonStringifyOptions) -> String {
+    if v.is_nan() {
+        return "nan".to_string();
+    }
+    if v.is_infinite() {
+        return if v < 0.0 { "-inf".to_string() } else { "inf".to_string() };
+    }
+    let s = match options.float_precision {
+        FloatPrecision::Shortest => v.to_string(),
+        FloatPrecision::Decimals(decimals) => format!("{:.*}", decimals, v)
+    };
+    if s.contains('.') {
+        s
+    } else {
+        format!("{}.0", s)
+    }
+}
+
+// Used by the grammar. Splits off the sign and drops _ separators, which str::parse doesn't accept
+fn split_number_literal(literal: &str) -> (&'static str, String) {
+    let digits: String = literal.chars().filter(|c| *c != '_' && *c != '+').collect();
+    if digits.starts_with("-") {
+        ("-", digits[1..].to_string())
+    } else {
+        ("", digits)
+    }
+}
+
+fn parse_float_literal(literal: &str) -> Result<f32, &'static str> {
+    let (sign, digits) = split_number_literal(literal);
+    match format!("{}{}", sign, digits).parse::<f32>() {
+        Ok(v) if v.is_finite() => Ok(v),
+        _ => Err("float literal within f32 range")
+    }
+}
+
+fn parse_integer_literal(literal: &str) -> Result<i64, &'static str> {
+    let (sign, digits) = split_number_literal(literal);
+    let res = if digits.starts_with("0x") || digits.starts_with("0X") {
+        i64::from_str_radix(&format!("{}{}", sign, &digits[2..]), 16)
+    } else {
+        format!("{}{}", sign, digits).parse::<i64>()
+    };
+    match res {
+        Ok(v) => Ok(v),
+        Err(_) => Err("integer literal within i64 range")
+    }
+}
+
+fn stringify_string(v: &str) -> String {
+    format!("'{}'", escape_string(v, &['\'']))
+}
+
+// Escapes backslashes, control characters and the given delimiters the way the pon grammar reads them
+pub fn escape_string(v: &str, delimiters: &[char]) -> String {
+    let mut s = String::new();
+    for c in v.chars() {
+        match c {
+            '\\' => s.push_str("\\\\"),
+            '\n' => s.push_str("\\n"),
+            '\t' => s.push_str("\\t"),
+            '\r' => s.push_str("\\r"),
+            '\0' => s.push_str("\\0"),
+            c if delimiters.contains(&c) => {
+                s.push('\\');
+                s.push(c);
+            },
+            c if c.is_control() => s.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            _ => s.push(c)
+        }
+    }
+    s
+}
+
+fn stringify_key(key: &str) -> String {
+    let is_identifier = key.len() > 0 && key.chars().enumerate().all(|(i, c)| match c {
+        'a'...'z' | 'A'...'Z' | '_' => true,
+        '0'...'9' => i > 0,
+        _ => false
+    });
+    if is_identifier {
+        key.to_string()
+    } else {
+        stringify_string(key)
+    }
+}
+
+fn stringify_typed_array(type_name: &str, values: Vec<String>, options: &PonStringifyOptions, level: usize, column: usize) -> String {
+    let column = column + type_name.chars().count() + 1;
+    format!("#{}{}", type_name, layout_list("[", "]", values, options, level, column))
+}
+
+// Puts the items on one line if they fit, otherwise one item per line, indented one level deeper
+// than the brackets
+fn layout_list(open: &str, close: &str, items: Vec<String>, options: &PonStringifyOptions, level: usize, column: usize) -> String {
+    if items.len() == 0 {
+        return format!("{}{}", open.trim(), close.trim());
+    }
+    if options.compact {
+        return format!("{}{}{}", open, items.join(","), close);
+    }
+    let line = format!("{}{}{}", open, items.join(", "), close);
+    if !line.contains('\n') && level * options.indent + column + line.chars().count() <= options.max_line_width {
+        return line;
+    }
+    let indent: String = (0..options.indent * (level + 1)).map(|_| ' ').collect();
+    let mut s = format!("{}\n", open.trim());
+    for (i, item) in items.iter().enumerate() {
+        s.push_str(&indent);
+        s.push_str(item);
+        if i + 1 < items.len() || options.trailing_commas {
+            s.push(',');
+        }
+        s.push('\n');
+    }
+    s.push_str(&indent[..options.indent * level]);
+    s.push_str(close.trim());
+    s
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum FloatPrecision {
+    // As many digits as needed to read back the exact same value
+    Shortest,
+    // A fixed number of decimals, for output meant for people rather than for reading back
+    Decimals(usize)
+}
+
 pub struct PonStringifyOptions {
-    pub unwrap_dependencies: bool
+    pub unwrap_dependencies: bool,
+    // Spaces per nesting level when a value is broken over several lines
+    pub indent: usize,
+    // Arrays and objects longer than this are broken into one item per line
+    pub max_line_width: usize,
+    pub trailing_commas: bool,
+    pub sort_keys: bool,
+    // Everything on one line, without optional whitespace
+    pub compact: bool,
+    pub float_precision: FloatPrecision,
+    // Write the comments kept in front of object keys. Left out in compact mode.
+    pub keep_comments: bool
 }
 impl PonStringifyOptions {
     pub fn default() -> PonStringifyOptions {
         PonStringifyOptions {
-            unwrap_dependencies: false
+            unwrap_dependencies: false,
+            indent: 2,
+            max_line_width: 120,
+            trailing_commas: false,
+            sort_keys: false,
+            compact: false,
+            float_precision: FloatPrecision::Shortest,
+            keep_comments: false
+        }
+    }
+    pub fn compact() -> PonStringifyOptions {
+        PonStringifyOptions {
+            compact: true,
+            ..PonStringifyOptions::default()
         }
     }
 }
 
 impl ToString for Pon {
     fn to_string(&self) -> String {
-        self.stringify(&PonStringifyOptions::default())
+        self.to_string_with(&PonStringifyOptions::default())
     }
 }
 
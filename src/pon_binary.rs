@@ -0,0 +1,686 @@
+// Binary encoding of pon values and documents, for loading large scenes without going through the
+// parser. Everything starts with a four byte magic and a u32 format version. After that a pon value
+// is a tag byte followed by its payload:
+//
+//   numbers          little-endian, i64 for integers and the bits of the f32 for floats
+//   strings          u32 byte length, then utf-8
+//   lists            u32 item count, then the items
+//   typed arrays     u32 count, then the raw little-endian values
+//   references       the entity path as a tree of path tags, then the property name
+//   expressions      an expression tag and the operands, so they don't need parsing either
+//
+// A document is a flag for whether there's a root entity, then the entities depth first as type
+// name, optional name, properties and children.
+
+use pon::*;
+use document::*;
+use cgmath::{Vector3, Vector4, Matrix4};
+use std::mem;
+
+const PON_MAGIC: &'static [u8] = b"PONV";
+const DOCUMENT_MAGIC: &'static [u8] = b"POND";
+// Bump when the encoding changes, older data is then rejected rather than misread
+pub const PON_BINARY_VERSION: u32 = 1;
+// How deeply values and entities can be nested, so corrupt or hostile data fails to load instead
+// of overflowing the stack
+const MAX_NESTING_DEPTH: usize = 256;
+
+const TAG_NIL: u8 = 0;
+const TAG_BOOLEAN: u8 = 1;
+const TAG_INTEGER: u8 = 2;
+const TAG_FLOAT: u8 = 3;
+const TAG_STRING: u8 = 4;
+const TAG_ARRAY: u8 = 5;
+const TAG_OBJECT: u8 = 6;
+const TAG_TYPED_PON: u8 = 7;
+const TAG_DEPENDENCY_REFERENCE: u8 = 8;
+const TAG_REFERENCE: u8 = 9;
+const TAG_FLOAT_ARRAY: u8 = 10;
+const TAG_INTEGER_ARRAY: u8 = 11;
+const TAG_VECTOR3: u8 = 12;
+const TAG_VECTOR4: u8 = 13;
+const TAG_MATRIX4: u8 = 14;
+const TAG_EXPRESSION: u8 = 15;
+
+const PATH_THIS: u8 = 0;
+const PATH_PARENT: u8 = 1;
+const PATH_NAMED: u8 = 2;
+const PATH_SEARCH: u8 = 3;
+
+const EXPRESSION_BINARY: u8 = 0;
+const EXPRESSION_NEG: u8 = 1;
+const EXPRESSION_CONDITIONAL: u8 = 2;
+const EXPRESSION_FALLBACK: u8 = 3;
+const EXPRESSION_TEMPLATE: u8 = 4;
+const EXPRESSION_CALL: u8 = 5;
+
+const TEMPLATE_TEXT: u8 = 0;
+const TEMPLATE_VALUE: u8 = 1;
+
+// Indexed by the operator byte
+const BINARY_OPERATORS: [BinaryOperator; 11] = [
+    BinaryOperator::Add, BinaryOperator::Sub, BinaryOperator::Mul, BinaryOperator::Div, BinaryOperator::Rem,
+    BinaryOperator::Equal, BinaryOperator::NotEqual, BinaryOperator::Less, BinaryOperator::LessOrEqual,
+    BinaryOperator::Greater, BinaryOperator::GreaterOrEqual
+];
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct PonBinaryError(pub String);
+
+impl ToString for PonBinaryError {
+    fn to_string(&self) -> String {
+        self.0.clone()
+    }
+}
+
+impl From<PonBinaryError> for DocError {
+    fn from(err: PonBinaryError) -> DocError {
+        DocError::Binary(err.0)
+    }
+}
+
+impl Pon {
+    pub fn to_binary(&self) -> Result<Vec<u8>, PonBinaryError> {
+        let mut out = vec![];
+        write_header(&mut out, PON_MAGIC);
+        try!(write_pon(&mut out, self));
+        Ok(out)
+    }
+    pub fn from_binary(data: &[u8]) -> Result<Pon, PonBinaryError> {
+        let mut reader = BinaryReader { data: data, pos: 0, depth: 0 };
+        try!(reader.read_header(PON_MAGIC));
+        let pon = try!(reader.read_pon());
+        try!(reader.finish());
+        Ok(pon)
+    }
+}
+
+impl Document {
+    pub fn to_binary(&self) -> Result<Vec<u8>, DocError> {
+        let mut out = vec![];
+        write_header(&mut out, DOCUMENT_MAGIC);
+        match self.snapshot().root {
+            Some(ref root) => {
+                out.push(1);
+                try!(write_entity(&mut out, root));
+            },
+            None => out.push(0)
+        }
+        Ok(out)
+    }
+    pub fn from_binary(data: &[u8]) -> Result<Document, DocError> {
+        Document::from_binary_with_report(data, &DocumentLoadOptions::default()).map(|(doc, _)| doc)
+    }
+    // Unresolved properties are reported the same way as for snapshots
+    pub fn from_binary_with_report(data: &[u8], options: &DocumentLoadOptions) -> Result<(Document, DocumentLoadReport), DocError> {
+        let mut reader = BinaryReader { data: data, pos: 0, depth: 0 };
+        try!(reader.read_header(DOCUMENT_MAGIC));
+        let root = match try!(reader.read_u8()) {
+            0 => None,
+            _ => Some(try!(reader.read_entity()))
+        };
+        try!(reader.finish());
+        Document::from_snapshot_with_report(&DocumentSnapshot { root: root }, options)
+    }
+}
+
+fn write_header(out: &mut Vec<u8>, magic: &[u8]) {
+    out.extend(magic.iter().cloned());
+    write_u32(out, PON_BINARY_VERSION);
+}
+
+fn write_u32(out: &mut Vec<u8>, v: u32) {
+    for i in 0..4 {
+        out.push((v >> (i * 8)) as u8);
+    }
+}
+
+fn write_u64(out: &mut Vec<u8>, v: u64) {
+    for i in 0..8 {
+        out.push((v >> (i * 8)) as u8);
+    }
+}
+
+fn write_f32(out: &mut Vec<u8>, v: f32) {
+    // the raw bits, so nan payloads and -0.0 survive too
+    write_u32(out, unsafe { mem::transmute::<f32, u32>(v) });
+}
+
+fn write_len(out: &mut Vec<u8>, len: usize) -> Result<(), PonBinaryError> {
+    if len > ::std::u32::MAX as usize {
+        return Err(PonBinaryError(format!("Too many items to encode: {}", len)));
+    }
+    write_u32(out, len as u32);
+    Ok(())
+}
+
+fn write_string(out: &mut Vec<u8>, v: &str) -> Result<(), PonBinaryError> {
+    try!(write_len(out, v.len()));
+    out.extend(v.as_bytes().iter().cloned());
+    Ok(())
+}
+
+fn write_floats(out: &mut Vec<u8>, values: &[f32]) -> Result<(), PonBinaryError> {
+    try!(write_len(out, values.len()));
+    for v in values {
+        write_f32(out, *v);
+    }
+    Ok(())
+}
+
+fn write_entity_path(out: &mut Vec<u8>, path: &EntityPath) -> Result<(), PonBinaryError> {
+    match path {
+        &EntityPath::This => out.push(PATH_THIS),
+        &EntityPath::Parent => out.push(PATH_PARENT),
+        &EntityPath::Named(ref name) => {
+            out.push(PATH_NAMED);
+            try!(write_string(out, name));
+        },
+        &EntityPath::Search(ref path, ref name) => {
+            out.push(PATH_SEARCH);
+            try!(write_entity_path(out, path));
+            try!(write_string(out, name));
+        }
+    }
+    Ok(())
+}
+
+fn write_named_prop_ref(out: &mut Vec<u8>, named_prop_ref: &NamedPropRef) -> Result<(), PonBinaryError> {
+    try!(write_entity_path(out, &named_prop_ref.entity_path));
+    try!(write_string(out, &named_prop_ref.property_key));
+    Ok(())
+}
+
+fn write_pon(out: &mut Vec<u8>, pon: &Pon) -> Result<(), PonBinaryError> {
+    match pon {
+        &Pon::Nil => out.push(TAG_NIL),
+        &Pon::Boolean(v) => {
+            out.push(TAG_BOOLEAN);
+            out.push(v as u8);
+        },
+        &Pon::Integer(v) => {
+            out.push(TAG_INTEGER);
+            write_u64(out, v as u64);
+        },
+        &Pon::Float(v) => {
+            out.push(TAG_FLOAT);
+            write_f32(out, v);
+        },
+        &Pon::String(ref v) => {
+            out.push(TAG_STRING);
+            try!(write_string(out, v));
+        },
+        &Pon::Array(ref array) => {
+            out.push(TAG_ARRAY);
+            try!(write_len(out, array.len()));
+            for v in array {
+                try!(write_pon(out, v));
+            }
+        },
+        &Pon::Object(ref hm) => {
+            out.push(TAG_OBJECT);
+            try!(write_len(out, hm.len()));
+            for (key, value) in hm {
+                try!(write_string(out, key));
+                try!(write_pon(out, value));
+            }
+        },
+        &Pon::TypedPon(box TypedPon { ref type_name, ref data }) => {
+            out.push(TAG_TYPED_PON);
+            try!(write_string(out, type_name));
+            try!(write_pon(out, data));
+        },
+        &Pon::DependencyReference(ref named_prop_ref, _) => {
+            out.push(TAG_DEPENDENCY_REFERENCE);
+            try!(write_named_prop_ref(out, named_prop_ref));
+        },
+        &Pon::Reference(ref named_prop_ref) => {
+            out.push(TAG_REFERENCE);
+            try!(write_named_prop_ref(out, named_prop_ref));
+        },
+        &Pon::FloatArray(ref v) => {
+            out.push(TAG_FLOAT_ARRAY);
+            try!(write_floats(out, v));
+        },
+        &Pon::IntegerArray(ref v) => {
+            out.push(TAG_INTEGER_ARRAY);
+            try!(write_len(out, v.len()));
+            for i in v {
+                write_u64(out, *i as u64);
+            }
+        },
+        &Pon::Vector3(ref v) => {
+            out.push(TAG_VECTOR3);
+            for v in &[v.x, v.y, v.z] {
+                write_f32(out, *v);
+            }
+        },
+        &Pon::Vector4(ref v) => {
+            out.push(TAG_VECTOR4);
+            for v in &[v.x, v.y, v.z, v.w] {
+                write_f32(out, *v);
+            }
+        },
+        &Pon::Matrix4(ref v) => {
+            out.push(TAG_MATRIX4);
+            for v in &[
+                    v.x.x, v.x.y, v.x.z, v.x.w,
+                    v.y.x, v.y.y, v.y.z, v.y.w,
+                    v.z.x, v.z.y, v.z.z, v.z.w,
+                    v.w.x, v.w.y, v.w.z, v.w.w
+                ] {
+                write_f32(out, *v);
+            }
+        },
+        &Pon::Expression(box ref expression) => {
+            out.push(TAG_EXPRESSION);
+            try!(write_expression(out, expression));
+        }
+    }
+    Ok(())
+}
+
+fn write_expression(out: &mut Vec<u8>, expression: &PonExpression) -> Result<(), PonBinaryError> {
+    match expression {
+        &PonExpression::Binary(op, ref left, ref right) => {
+            out.push(EXPRESSION_BINARY);
+            out.push(BINARY_OPERATORS.iter().position(|x| *x == op).unwrap() as u8);
+            try!(write_pon(out, left));
+            try!(write_pon(out, right));
+        },
+        &PonExpression::Unary(UnaryOperator::Neg, ref operand) => {
+            out.push(EXPRESSION_NEG);
+            try!(write_pon(out, operand));
+        },
+        &PonExpression::Conditional(ref condition, ref a, ref b) => {
+            out.push(EXPRESSION_CONDITIONAL);
+            try!(write_pon(out, condition));
+            try!(write_pon(out, a));
+            try!(write_pon(out, b));
+        },
+        &PonExpression::Fallback(ref left, ref right) => {
+            out.push(EXPRESSION_FALLBACK);
+            try!(write_pon(out, left));
+            try!(write_pon(out, right));
+        },
+        &PonExpression::Template(ref parts) => {
+            out.push(EXPRESSION_TEMPLATE);
+            try!(write_len(out, parts.len()));
+            for part in parts {
+                match part {
+                    &TemplatePart::Text(ref text) => {
+                        out.push(TEMPLATE_TEXT);
+                        try!(write_string(out, text));
+                    },
+                    &TemplatePart::Value(ref v) => {
+                        out.push(TEMPLATE_VALUE);
+                        try!(write_pon(out, v));
+                    }
+                }
+            }
+        },
+        &PonExpression::Call(ref name, ref args) => {
+            out.push(EXPRESSION_CALL);
+            try!(write_string(out, name));
+            try!(write_len(out, args.len()));
+            for v in args {
+                try!(write_pon(out, v));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_entity(out: &mut Vec<u8>, entity: &EntitySnapshot) -> Result<(), PonBinaryError> {
+    try!(write_string(out, &entity.type_name));
+    match entity.name {
+        Some(ref name) => {
+            out.push(1);
+            try!(write_string(out, name));
+        },
+        None => out.push(0)
+    }
+    try!(write_len(out, entity.properties.len()));
+    for &(ref key, ref value) in &entity.properties {
+        try!(write_string(out, key));
+        try!(write_pon(out, value));
+    }
+    try!(write_len(out, entity.children.len()));
+    for child in &entity.children {
+        try!(write_entity(out, child));
+    }
+    Ok(())
+}
+
+struct BinaryReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    depth: usize
+}
+
+impl<'a> BinaryReader<'a> {
+    fn error<T>(&self, message: String) -> Result<T, PonBinaryError> {
+        Err(PonBinaryError(format!("{} at byte {}", message, self.pos)))
+    }
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], PonBinaryError> {
+        if self.data.len() - self.pos < len {
+            return self.error(format!("Unexpected end of data, expected {} more bytes", len));
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+    fn read_u8(&mut self) -> Result<u8, PonBinaryError> {
+        Ok(try!(self.read_bytes(1))[0])
+    }
+    fn read_u32(&mut self) -> Result<u32, PonBinaryError> {
+        let bytes = try!(self.read_bytes(4));
+        Ok(bytes.iter().enumerate().fold(0, |v, (i, b)| v | (*b as u32) << (i * 8)))
+    }
+    fn read_u64(&mut self) -> Result<u64, PonBinaryError> {
+        let bytes = try!(self.read_bytes(8));
+        Ok(bytes.iter().enumerate().fold(0, |v, (i, b)| v | (*b as u64) << (i * 8)))
+    }
+    fn read_f32(&mut self) -> Result<f32, PonBinaryError> {
+        let bits = try!(self.read_u32());
+        Ok(unsafe { mem::transmute::<u32, f32>(bits) })
+    }
+    // A length prefix, checked against what's left so corrupt data can't make us allocate huge
+    // vectors. Every item takes at least min_item_size bytes.
+    fn read_len(&mut self, min_item_size: usize) -> Result<usize, PonBinaryError> {
+        let len = try!(self.read_u32()) as usize;
+        match len.checked_mul(min_item_size) {
+            Some(size) if size <= self.data.len() - self.pos => Ok(len),
+            _ => self.error(format!("Length {} is longer than the data", len))
+        }
+    }
+    // Runs read one level deeper
+    fn nested<T, F: FnOnce(&mut BinaryReader<'a>) -> Result<T, PonBinaryError>>(&mut self, read: F) -> Result<T, PonBinaryError> {
+        if self.depth >= MAX_NESTING_DEPTH {
+            return self.error(format!("Nested more than {} levels deep", MAX_NESTING_DEPTH));
+        }
+        self.depth += 1;
+        let res = read(self);
+        self.depth -= 1;
+        res
+    }
+    fn read_string(&mut self) -> Result<String, PonBinaryError> {
+        let len = try!(self.read_len(1));
+        let bytes = try!(self.read_bytes(len));
+        match String::from_utf8(bytes.to_vec()) {
+            Ok(v) => Ok(v),
+            Err(_) => self.error("Invalid utf-8 in string".to_string())
+        }
+    }
+    fn read_floats(&mut self, len: usize) -> Result<Vec<f32>, PonBinaryError> {
+        let mut out = Vec::with_capacity(len);
+        for _ in 0..len {
+            out.push(try!(self.read_f32()));
+        }
+        Ok(out)
+    }
+    fn read_header(&mut self, magic: &[u8]) -> Result<(), PonBinaryError> {
+        if try!(self.read_bytes(magic.len())) != magic {
+            return self.error(format!("Expected the magic {:?}", String::from_utf8_lossy(magic)));
+        }
+        let version = try!(self.read_u32());
+        if version != PON_BINARY_VERSION {
+            return self.error(format!("Unsupported version {}, expected {}", version, PON_BINARY_VERSION));
+        }
+        Ok(())
+    }
+    fn finish(&self) -> Result<(), PonBinaryError> {
+        if self.pos < self.data.len() {
+            return self.error(format!("{} bytes of trailing data", self.data.len() - self.pos));
+        }
+        Ok(())
+    }
+    fn read_entity_path(&mut self) -> Result<EntityPath, PonBinaryError> {
+        self.nested(|reader| reader.read_entity_path_inner())
+    }
+    fn read_entity_path_inner(&mut self) -> Result<EntityPath, PonBinaryError> {
+        match try!(self.read_u8()) {
+            PATH_THIS => Ok(EntityPath::This),
+            PATH_PARENT => Ok(EntityPath::Parent),
+            PATH_NAMED => Ok(EntityPath::Named(try!(self.read_string()))),
+            PATH_SEARCH => {
+                let path = try!(self.read_entity_path());
+                Ok(EntityPath::Search(Box::new(path), try!(self.read_string())))
+            },
+            tag => self.error(format!("Unknown entity path tag {}", tag))
+        }
+    }
+    fn read_named_prop_ref(&mut self) -> Result<NamedPropRef, PonBinaryError> {
+        let entity_path = try!(self.read_entity_path());
+        Ok(NamedPropRef {
+            entity_path: entity_path,
+            property_key: try!(self.read_string())
+        })
+    }
+    fn read_pon(&mut self) -> Result<Pon, PonBinaryError> {
+        self.nested(|reader| reader.read_pon_inner())
+    }
+    fn read_pon_inner(&mut self) -> Result<Pon, PonBinaryError> {
+        match try!(self.read_u8()) {
+            TAG_NIL => Ok(Pon::Nil),
+            TAG_BOOLEAN => match try!(self.read_u8()) {
+                0 => Ok(Pon::Boolean(false)),
+                1 => Ok(Pon::Boolean(true)),
+                v => self.error(format!("Invalid boolean {}", v))
+            },
+            TAG_INTEGER => Ok(Pon::Integer(try!(self.read_u64()) as i64)),
+            TAG_FLOAT => Ok(Pon::Float(try!(self.read_f32()))),
+            TAG_STRING => Ok(Pon::String(try!(self.read_string()))),
+            TAG_ARRAY => {
+                let len = try!(self.read_len(1));
+                let mut array = Vec::with_capacity(len);
+                for _ in 0..len {
+                    array.push(try!(self.read_pon()));
+                }
+                Ok(Pon::Array(array))
+            },
+            TAG_OBJECT => {
+                let len = try!(self.read_len(5));
+                let mut object = PonObject::new();
+                for _ in 0..len {
+                    let key = try!(self.read_string());
+                    object.insert(key, try!(self.read_pon()));
+                }
+                Ok(Pon::Object(object))
+            },
+            TAG_TYPED_PON => {
+                let type_name = try!(self.read_string());
+                Ok(Pon::new_typed_pon(&type_name, try!(self.read_pon())))
+            },
+            TAG_DEPENDENCY_REFERENCE => Ok(Pon::DependencyReference(try!(self.read_named_prop_ref()), None)),
+            TAG_REFERENCE => Ok(Pon::Reference(try!(self.read_named_prop_ref()))),
+            TAG_FLOAT_ARRAY => {
+                let len = try!(self.read_len(4));
+                Ok(Pon::FloatArray(try!(self.read_floats(len))))
+            },
+            TAG_INTEGER_ARRAY => {
+                let len = try!(self.read_len(8));
+                let mut array = Vec::with_capacity(len);
+                for _ in 0..len {
+                    array.push(try!(self.read_u64()) as i64);
+                }
+                Ok(Pon::IntegerArray(array))
+            },
+            TAG_VECTOR3 => {
+                let v = try!(self.read_floats(3));
+                Ok(Pon::Vector3(Vector3::new(v[0], v[1], v[2])))
+            },
+            TAG_VECTOR4 => {
+                let v = try!(self.read_floats(4));
+                Ok(Pon::Vector4(Vector4::new(v[0], v[1], v[2], v[3])))
+            },
+            TAG_MATRIX4 => {
+                let v = try!(self.read_floats(16));
+                Ok(Pon::Matrix4(Matrix4::new(
+                    v[0], v[1], v[2], v[3],
+                    v[4], v[5], v[6], v[7],
+                    v[8], v[9], v[10], v[11],
+                    v[12], v[13], v[14], v[15])))
+            },
+            TAG_EXPRESSION => Ok(Pon::Expression(Box::new(try!(self.read_expression())))),
+            tag => self.error(format!("Unknown tag {}", tag))
+        }
+    }
+    fn read_expression(&mut self) -> Result<PonExpression, PonBinaryError> {
+        match try!(self.read_u8()) {
+            EXPRESSION_BINARY => {
+                let op = match BINARY_OPERATORS.get(try!(self.read_u8()) as usize) {
+                    Some(op) => *op,
+                    None => return self.error("Unknown binary operator".to_string())
+                };
+                let left = try!(self.read_pon());
+                Ok(PonExpression::Binary(op, left, try!(self.read_pon())))
+            },
+            EXPRESSION_NEG => Ok(PonExpression::Unary(UnaryOperator::Neg, try!(self.read_pon()))),
+            EXPRESSION_CONDITIONAL => {
+                let condition = try!(self.read_pon());
+                let a = try!(self.read_pon());
+                Ok(PonExpression::Conditional(condition, a, try!(self.read_pon())))
+            },
+            EXPRESSION_FALLBACK => {
+                let left = try!(self.read_pon());
+                Ok(PonExpression::Fallback(left, try!(self.read_pon())))
+            },
+            EXPRESSION_TEMPLATE => {
+                let len = try!(self.read_len(1));
+                let mut parts = Vec::with_capacity(len);
+                for _ in 0..len {
+                    parts.push(match try!(self.read_u8()) {
+                        TEMPLATE_TEXT => TemplatePart::Text(try!(self.read_string())),
+                        TEMPLATE_VALUE => TemplatePart::Value(try!(self.read_pon())),
+                        tag => return self.error(format!("Unknown template part tag {}", tag))
+                    });
+                }
+                Ok(PonExpression::Template(parts))
+            },
+            EXPRESSION_CALL => {
+                let name = try!(self.read_string());
+                let len = try!(self.read_len(1));
+                let mut args = Vec::with_capacity(len);
+                for _ in 0..len {
+                    args.push(try!(self.read_pon()));
+                }
+                Ok(PonExpression::Call(name, args))
+            },
+            tag => self.error(format!("Unknown expression tag {}", tag))
+        }
+    }
+    fn read_entity(&mut self) -> Result<EntitySnapshot, PonBinaryError> {
+        self.nested(|reader| reader.read_entity_inner())
+    }
+    fn read_entity_inner(&mut self) -> Result<EntitySnapshot, PonBinaryError> {
+        let type_name = try!(self.read_string());
+        let name = match try!(self.read_u8()) {
+            0 => None,
+            _ => Some(try!(self.read_string()))
+        };
+        let len = try!(self.read_len(5));
+        let mut properties = Vec::with_capacity(len);
+        for _ in 0..len {
+            let key = try!(self.read_string());
+            properties.push((key, try!(self.read_pon())));
+        }
+        let len = try!(self.read_len(13));
+        let mut children = Vec::with_capacity(len);
+        for _ in 0..len {
+            children.push(try!(self.read_entity()));
+        }
+        Ok(EntitySnapshot {
+            type_name: type_name,
+            name: name,
+            properties: properties,
+            children: children
+        })
+    }
+}
+
+
+#[test]
+fn test_binary_round_trip() {
+    let text = "{ z: [1, -2, (), true, 'text'], a: static_mesh { vertices: #f32[0.0, 1.5, -0.0, nan, inf] }, \
+        b: #i64[1, -9223372036854775808], c: #vec3[1.0, 2.0, 3.0], d: #vec4[1.0, 2.0, 3.0, 4.0], \
+        e: #mat4[1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 5.0, 6.0, 7.0, 1.0], \
+        f: @parent:cam.fov * -@this.x + 0.1, g: if @this.x > 1 then this.y else `x: ${@this.x ?? 0}`, \
+        'h i': parent.x }";
+    let pon = Pon::from_string(text).unwrap();
+    let copy = Pon::from_binary(&pon.to_binary().unwrap()).unwrap();
+    assert_eq!(copy.to_string(), pon.to_string());
+    assert_eq!(copy.to_binary().unwrap(), pon.to_binary().unwrap());
+}
+
+#[test]
+fn test_binary_float_array_layout() {
+    let data = Pon::FloatArray(vec![1.0, -2.0]).to_binary().unwrap();
+    assert_eq!(&data[..8], b"PONV\x01\x00\x00\x00");
+    assert_eq!(&data[8..], &[TAG_FLOAT_ARRAY, 2, 0, 0, 0, 0x00, 0x00, 0x80, 0x3f, 0x00, 0x00, 0x00, 0xc0][..]);
+}
+
+#[test]
+fn test_binary_errors() {
+    let data = Pon::from_string("[1, 'text']").unwrap().to_binary().unwrap();
+    assert!(Pon::from_binary(&data[..data.len() - 1]).is_err());
+    let mut trailing = data.clone();
+    trailing.push(0);
+    assert!(Pon::from_binary(&trailing).is_err());
+    assert!(Pon::from_binary(b"PONV\x02\x00\x00\x00\x00").is_err());
+    assert!(Pon::from_binary(b"PONV\x01\x00\x00\x00\xff").is_err());
+    assert!(Pon::from_binary(b"PONV\x01\x00\x00\x00\x05\xff\xff\xff\xff").is_err());
+    assert!(Document::from_binary(&data).is_err());
+}
+
+#[test]
+fn test_binary_too_many_items() {
+    let mut data = vec![];
+    assert_eq!(write_len(&mut data, ::std::u32::MAX as usize + 1).err().unwrap(),
+        PonBinaryError(format!("Too many items to encode: {}", ::std::u32::MAX as usize + 1)));
+    assert_eq!(data.len(), 0);
+    assert_eq!(DocError::from(PonBinaryError("x".to_string())), DocError::Binary("x".to_string()));
+}
+
+#[test]
+fn test_binary_nesting_limit() {
+    let nested = |depth: usize| {
+        let mut data = b"PONV\x01\x00\x00\x00".to_vec();
+        for _ in 0..depth {
+            data.extend([TAG_ARRAY, 1, 0, 0, 0].iter().cloned());
+        }
+        data.push(TAG_NIL);
+        data
+    };
+    assert!(Pon::from_binary(&nested(MAX_NESTING_DEPTH - 1)).is_ok());
+    assert!(Pon::from_binary(&nested(MAX_NESTING_DEPTH)).is_err());
+    assert!(Pon::from_binary(&nested(1000000)).is_err());
+}
+
+#[test]
+fn test_document_binary_report() {
+    let mut data = vec![];
+    write_header(&mut data, DOCUMENT_MAGIC);
+    data.push(1);
+    write_entity(&mut data, &EntitySnapshot {
+        type_name: "Entity".to_string(),
+        name: None,
+        properties: vec![("y".to_string(), Pon::from_string("@what.x").unwrap())],
+        children: vec![]
+    }).unwrap();
+    let (_, report) = Document::from_binary_with_report(&data, &DocumentLoadOptions::default()).unwrap();
+    assert_eq!(report.diagnostics.len(), 1);
+    assert_eq!(report.diagnostics[0].kind, LoadDiagnosticKind::UnresolvedProperty);
+    assert!(Document::from_binary_with_report(&data, &DocumentLoadOptions { strict: true }).is_err());
+}
+
+#[test]
+fn test_document_binary_round_trip() {
+    let doc = Document::from_string(r#"<Entity name="root" x="5.0"><Sprite name="s" mesh="static_mesh { vertices: #f32[0.0, 1.0] }" y="@root.x * 2" /><Entity /></Entity>"#).unwrap();
+    let copy = Document::from_binary(&doc.to_binary().unwrap()).unwrap();
+    assert_eq!(copy.snapshot(), doc.snapshot());
+    assert_eq!(copy.to_string(), doc.to_string());
+    let sprite = copy.get_entity_by_name("s").unwrap();
+    assert_eq!(copy.get_property(&sprite, "y").unwrap().concretize().unwrap(), Pon::Float(10.0));
+    assert_eq!(Document::from_binary(&Document::new().to_binary().unwrap()).unwrap().get_root(), None);
+}
@@ -0,0 +1,386 @@
+use pon::*;
+use cgmath::*;
+use std::cmp::Ordering;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
+pub enum BinaryOperator {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual
+}
+impl BinaryOperator {
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            &BinaryOperator::Add => "+",
+            &BinaryOperator::Sub => "-",
+            &BinaryOperator::Mul => "*",
+            &BinaryOperator::Div => "/",
+            &BinaryOperator::Rem => "%",
+            &BinaryOperator::Equal => "==",
+            &BinaryOperator::NotEqual => "!=",
+            &BinaryOperator::Less => "<",
+            &BinaryOperator::LessOrEqual => "<=",
+            &BinaryOperator::Greater => ">",
+            &BinaryOperator::GreaterOrEqual => ">="
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
+pub enum UnaryOperator {
+    Neg
+}
+
+// An expression node in a pon tree. It's evaluated lazily, every time the value is asked for
+// through concretize, as_resolved or translate, so it always reflects the current values of
+// the properties it references. A change to the branch of an if that isn't taken doesn't cascade
+// to the property, see Pon::get_active_dependency_references.
+#[derive(PartialEq, Debug, Clone)]
+pub enum PonExpression {
+    Binary(BinaryOperator, Pon, Pon),
+    Unary(UnaryOperator, Pon),
+    // if condition then a else b
+    Conditional(Pon, Pon, Pon),
+    // Right side is used when the left side is () or references a property that doesn't exist
+    Fallback(Pon, Pon),
+    // `Score: ${@game.score}`
+    Template(Vec<TemplatePart>),
+    // max(@this.x, 0.0), calls a function registered with register_pon_function. Needs at least
+    // one argument to be written and read back, name() is a typed value.
+    Call(String, Vec<Pon>)
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum TemplatePart {
+    Text(String),
+    Value(Pon)
+}
+
+impl PonExpression {
+    pub fn operands(&self) -> Vec<&Pon> {
+        match self {
+            &PonExpression::Binary(_, ref left, ref right) => vec![left, right],
+            &PonExpression::Unary(_, ref operand) => vec![operand],
+            &PonExpression::Conditional(ref condition, ref a, ref b) => vec![condition, a, b],
+            &PonExpression::Fallback(ref left, ref right) => vec![left, right],
+            &PonExpression::Template(ref parts) => parts.iter().filter_map(|part| match part {
+                &TemplatePart::Value(ref v) => Some(v),
+                &TemplatePart::Text(_) => None
+            }).collect(),
+            &PonExpression::Call(_, ref args) => args.iter().collect()
+        }
+    }
+    pub fn operands_mut(&mut self) -> Vec<&mut Pon> {
+        match self {
+            &mut PonExpression::Binary(_, ref mut left, ref mut right) => vec![left, right],
+            &mut PonExpression::Unary(_, ref mut operand) => vec![operand],
+            &mut PonExpression::Conditional(ref mut condition, ref mut a, ref mut b) => vec![condition, a, b],
+            &mut PonExpression::Fallback(ref mut left, ref mut right) => vec![left, right],
+            &mut PonExpression::Template(ref mut parts) => parts.iter_mut().filter_map(|part| match part {
+                &mut TemplatePart::Value(ref mut v) => Some(v),
+                &mut TemplatePart::Text(_) => None
+            }).collect(),
+            &mut PonExpression::Call(_, ref mut args) => args.iter_mut().collect()
+        }
+    }
+    pub fn evaluate(&self) -> Result<Pon, PonTranslateErr> {
+        match self {
+            &PonExpression::Binary(op, ref left, ref right) => {
+                let left = to_operand(try!(left.concretize()));
+                let right = to_operand(try!(right.concretize()));
+                match op {
+                    BinaryOperator::Add | BinaryOperator::Sub | BinaryOperator::Mul |
+                    BinaryOperator::Div | BinaryOperator::Rem => arithmetic(op, left, right),
+                    _ => compare(op, left, right)
+                }
+            },
+            &PonExpression::Unary(UnaryOperator::Neg, ref operand) => {
+                match to_operand(try!(operand.concretize())) {
+                    Operand::Integer(v) => match v.checked_neg() {
+                        Some(v) => Ok(Pon::Integer(v)),
+                        None => Err(PonTranslateErr::InvalidValue { value: format!("-{}", v) })
+                    },
+                    Operand::Float(v) => Ok(Pon::Float(-v)),
+                    Operand::Vector3(v) => Ok(Pon::Vector3(v.mul_s(-1.0))),
+                    Operand::Vector4(v) => Ok(Pon::Vector4(v.mul_s(-1.0))),
+                    operand => Err(PonTranslateErr::MismatchType { expected: "Number or vector".to_string(), found: operand.into_pon().to_string() })
+                }
+            },
+            // Only the branch that's taken is evaluated
+            &PonExpression::Conditional(ref condition, ref a, ref b) => {
+                match try!(condition.concretize()) {
+                    Pon::Boolean(true) => a.concretize(),
+                    Pon::Boolean(false) => b.concretize(),
+                    other => Err(PonTranslateErr::MismatchType { expected: "Boolean".to_string(), found: other.to_string() })
+                }
+            },
+            &PonExpression::Fallback(ref left, ref right) => {
+                match left.concretize() {
+                    Ok(Pon::Nil) | Err(PonTranslateErr::ReferenceToNonExistentProperty(_)) => right.concretize(),
+                    res => res
+                }
+            },
+            // Strings are inserted as is, anything else the way it's written in pon
+            &PonExpression::Template(ref parts) => {
+                let mut out = String::new();
+                for part in parts {
+                    match part {
+                        &TemplatePart::Text(ref text) => out.push_str(text),
+                        &TemplatePart::Value(ref v) => match try!(v.concretize()) {
+                            Pon::String(ref v) => out.push_str(v),
+                            v => out.push_str(&v.to_string())
+                        }
+                    }
+                }
+                Ok(Pon::String(out))
+            },
+            &PonExpression::Call(ref name, ref args) => {
+                let data = if args.len() == 1 { args[0].clone() } else { Pon::Array(args.clone()) };
+                match call_pon_function(name, &data) {
+                    Some(result) => result,
+                    None => Err(PonTranslateErr::Generic(format!("No such function: {}", name)))
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Operand {
+    Integer(i64),
+    Float(f32),
+    Vector3(Vector3<f32>),
+    Vector4(Vector4<f32>),
+    Matrix4(Matrix4<f32>),
+    Other(Pon)
+}
+
+impl Operand {
+    fn scalar(&self) -> Option<f32> {
+        match self {
+            &Operand::Integer(v) => Some(v as f32),
+            &Operand::Float(v) => Some(v),
+            _ => None
+        }
+    }
+    fn into_pon(self) -> Pon {
+        match self {
+            Operand::Integer(v) => Pon::Integer(v),
+            Operand::Float(v) => Pon::Float(v),
+            Operand::Vector3(v) => Pon::Vector3(v),
+            Operand::Vector4(v) => Pon::Vector4(v),
+            Operand::Matrix4(v) => Pon::Matrix4(v),
+            Operand::Other(v) => v
+        }
+    }
+}
+
+// Typed pons like vec3 { .. } or translate { .. } take part in arithmetic as their cgmath values
+fn to_operand(pon: Pon) -> Operand {
+    let translated = match &pon {
+        &Pon::TypedPon(box TypedPon { ref type_name, .. }) => {
+            let mut context = TranslateContext::empty();
+            match &type_name[..] {
+                "vec3" => pon.translate::<Vector3<f32>>(&mut context).ok().map(Operand::Vector3),
+                "vec4" => pon.translate::<Vector4<f32>>(&mut context).ok().map(Operand::Vector4),
+                _ => pon.translate::<Matrix4<f32>>(&mut context).ok().map(Operand::Matrix4)
+            }
+        },
+        _ => None
+    };
+    if let Some(operand) = translated {
+        return operand;
+    }
+    match pon {
+        Pon::Integer(v) => Operand::Integer(v),
+        Pon::Float(v) => Operand::Float(v),
+        Pon::Vector3(v) => Operand::Vector3(v),
+        Pon::Vector4(v) => Operand::Vector4(v),
+        Pon::Matrix4(v) => Operand::Matrix4(v),
+        pon => Operand::Other(pon)
+    }
+}
+
+fn mismatch(op: BinaryOperator, left: Operand, right: Operand) -> PonTranslateErr {
+    PonTranslateErr::MismatchType {
+        expected: format!("operands supporting {}", op.symbol()),
+        found: format!("{} {} {}", left.into_pon().to_string(), op.symbol(), right.into_pon().to_string())
+    }
+}
+
+fn integer_arithmetic(op: BinaryOperator, a: i64, b: i64) -> Result<Pon, PonTranslateErr> {
+    let res = match op {
+        BinaryOperator::Add => a.checked_add(b),
+        BinaryOperator::Sub => a.checked_sub(b),
+        BinaryOperator::Mul => a.checked_mul(b),
+        BinaryOperator::Div => a.checked_div(b),
+        BinaryOperator::Rem => a.checked_rem(b),
+        _ => unreachable!()
+    };
+    match res {
+        Some(v) => Ok(Pon::Integer(v)),
+        None => Err(PonTranslateErr::InvalidValue { value: format!("{} {} {}", a, op.symbol(), b) })
+    }
+}
+
+fn float_arithmetic(op: BinaryOperator, a: f32, b: f32) -> f32 {
+    match op {
+        BinaryOperator::Add => a + b,
+        BinaryOperator::Sub => a - b,
+        BinaryOperator::Mul => a * b,
+        BinaryOperator::Div => a / b,
+        BinaryOperator::Rem => a % b,
+        _ => unreachable!()
+    }
+}
+
+fn arithmetic(op: BinaryOperator, left: Operand, right: Operand) -> Result<Pon, PonTranslateErr> {
+    let result = match (op, &left, &right) {
+        (_, &Operand::Integer(a), &Operand::Integer(b)) => return integer_arithmetic(op, a, b),
+        (BinaryOperator::Add, &Operand::Other(Pon::String(ref a)), &Operand::Other(Pon::String(ref b))) =>
+            Some(Pon::String(format!("{}{}", a, b))),
+        (BinaryOperator::Add, &Operand::Vector3(ref a), &Operand::Vector3(ref b)) => Some(Pon::Vector3(a.add_v(b))),
+        (BinaryOperator::Sub, &Operand::Vector3(ref a), &Operand::Vector3(ref b)) => Some(Pon::Vector3(a.sub_v(b))),
+        (BinaryOperator::Mul, &Operand::Vector3(ref a), &Operand::Vector3(ref b)) => Some(Pon::Vector3(a.mul_v(b))),
+        (BinaryOperator::Div, &Operand::Vector3(ref a), &Operand::Vector3(ref b)) => Some(Pon::Vector3(a.div_v(b))),
+        (BinaryOperator::Add, &Operand::Vector4(ref a), &Operand::Vector4(ref b)) => Some(Pon::Vector4(a.add_v(b))),
+        (BinaryOperator::Sub, &Operand::Vector4(ref a), &Operand::Vector4(ref b)) => Some(Pon::Vector4(a.sub_v(b))),
+        (BinaryOperator::Mul, &Operand::Vector4(ref a), &Operand::Vector4(ref b)) => Some(Pon::Vector4(a.mul_v(b))),
+        (BinaryOperator::Div, &Operand::Vector4(ref a), &Operand::Vector4(ref b)) => Some(Pon::Vector4(a.div_v(b))),
+        (BinaryOperator::Mul, &Operand::Matrix4(ref a), &Operand::Matrix4(ref b)) => Some(Pon::Matrix4(*a * *b)),
+        (BinaryOperator::Mul, &Operand::Matrix4(ref a), &Operand::Vector4(ref b)) => Some(Pon::Vector4(a.mul_v(b))),
+        (BinaryOperator::Mul, &Operand::Vector3(ref a), b) => b.scalar().map(|s| Pon::Vector3(a.mul_s(s))),
+        (BinaryOperator::Div, &Operand::Vector3(ref a), b) => b.scalar().map(|s| Pon::Vector3(a.div_s(s))),
+        (BinaryOperator::Mul, &Operand::Vector4(ref a), b) => b.scalar().map(|s| Pon::Vector4(a.mul_s(s))),
+        (BinaryOperator::Div, &Operand::Vector4(ref a), b) => b.scalar().map(|s| Pon::Vector4(a.div_s(s))),
+        (BinaryOperator::Mul, &Operand::Matrix4(ref a), b) => b.scalar().map(|s| Pon::Matrix4(a.mul_s(s))),
+        (BinaryOperator::Mul, a, &Operand::Vector3(ref b)) => a.scalar().map(|s| Pon::Vector3(b.mul_s(s))),
+        (BinaryOperator::Mul, a, &Operand::Vector4(ref b)) => a.scalar().map(|s| Pon::Vector4(b.mul_s(s))),
+        (BinaryOperator::Mul, a, &Operand::Matrix4(ref b)) => a.scalar().map(|s| Pon::Matrix4(b.mul_s(s))),
+        (_, a, b) => match (a.scalar(), b.scalar()) {
+            (Some(a), Some(b)) => Some(Pon::Float(float_arithmetic(op, a, b))),
+            _ => None
+        }
+    };
+    match result {
+        Some(value) => Ok(value),
+        None => Err(mismatch(op, left, right))
+    }
+}
+
+fn compare(op: BinaryOperator, left: Operand, right: Operand) -> Result<Pon, PonTranslateErr> {
+    let ordering = match (&left, &right) {
+        (&Operand::Other(Pon::String(ref a)), &Operand::Other(Pon::String(ref b))) => Some(Some(a.cmp(b))),
+        // as f32 integers above 2^24 would lose precision
+        (&Operand::Integer(a), &Operand::Integer(b)) => Some(Some(a.cmp(&b))),
+        (a, b) => match (a.scalar(), b.scalar()) {
+            (Some(a), Some(b)) => Some(a.partial_cmp(&b)),
+            _ => None
+        }
+    };
+    let ordering = match ordering {
+        Some(ordering) => ordering,
+        // Anything else can only be tested for equality
+        None => return match op {
+            BinaryOperator::Equal => Ok(Pon::Boolean(left.into_pon() == right.into_pon())),
+            BinaryOperator::NotEqual => Ok(Pon::Boolean(left.into_pon() != right.into_pon())),
+            _ => Err(mismatch(op, left, right))
+        }
+    };
+    Ok(Pon::Boolean(match ordering {
+        Some(ordering) => match op {
+            BinaryOperator::Equal => ordering == Ordering::Equal,
+            BinaryOperator::NotEqual => ordering != Ordering::Equal,
+            BinaryOperator::Less => ordering == Ordering::Less,
+            BinaryOperator::LessOrEqual => ordering != Ordering::Greater,
+            BinaryOperator::Greater => ordering == Ordering::Greater,
+            BinaryOperator::GreaterOrEqual => ordering != Ordering::Less,
+            _ => unreachable!()
+        },
+        // comparisons with nan are always false, except for !=
+        None => op == BinaryOperator::NotEqual
+    }))
+}
+
+
+#[test]
+fn test_compare_large_integers() {
+    assert_eq!(Pon::from_string("16777217 == 16777216").unwrap().concretize(), Ok(Pon::Boolean(false)));
+    assert_eq!(Pon::from_string("16777217 > 16777216").unwrap().concretize(), Ok(Pon::Boolean(true)));
+    assert_eq!(Pon::from_string("16777216 < 16777217").unwrap().concretize(), Ok(Pon::Boolean(true)));
+}
+
+#[test]
+fn test_evaluate_conditional() {
+    assert_eq!(Pon::from_string("if 1 < 2 then 'red' else 'grey'").unwrap().concretize(), Ok(Pon::String("red".to_string())));
+    assert_eq!(Pon::from_string("false ? 1 : 2").unwrap().concretize(), Ok(Pon::Integer(2)));
+    // the branch not taken is never evaluated
+    assert_eq!(Pon::from_string("true ? 1 : 1 / 0").unwrap().concretize(), Ok(Pon::Integer(1)));
+    assert!(Pon::from_string("5 ? 1 : 2").unwrap().concretize().is_err());
+}
+
+#[test]
+fn test_evaluate_fallback() {
+    assert_eq!(Pon::from_string("() ?? 5.0").unwrap().concretize(), Ok(Pon::Float(5.0)));
+    assert_eq!(Pon::from_string("1 ?? 5.0").unwrap().concretize(), Ok(Pon::Integer(1)));
+    assert!(Pon::from_string("1 / 0 ?? 5.0").unwrap().concretize().is_err());
+}
+
+#[test]
+fn test_evaluate_template() {
+    assert_eq!(Pon::from_string("`Score: ${1 + 2}, name: ${'bob'}, ${[1.5]}`").unwrap().concretize(),
+        Ok(Pon::String("Score: 3, name: bob, [1.5]".to_string())));
+}
+
+#[test]
+fn test_evaluate_arithmetic() {
+    let pon = Pon::from_string("1 + 2 * 3 - 4 / 2").unwrap();
+    assert_eq!(pon.concretize(), Ok(Pon::Integer(5)));
+    let pon = Pon::from_string("(1 + 2) * 1.5").unwrap();
+    assert_eq!(pon.concretize(), Ok(Pon::Float(4.5)));
+    let pon = Pon::from_string("-(2) - -3").unwrap();
+    assert_eq!(pon.concretize(), Ok(Pon::Integer(1)));
+}
+
+#[test]
+fn test_evaluate_integer_errors() {
+    assert!(Pon::from_string("1 / 0").unwrap().concretize().is_err());
+    assert!(Pon::from_string("9223372036854775807 + 1").unwrap().concretize().is_err());
+}
+
+#[test]
+fn test_evaluate_comparison() {
+    assert_eq!(Pon::from_string("1 < 2.5").unwrap().concretize(), Ok(Pon::Boolean(true)));
+    assert_eq!(Pon::from_string("2 == 2.0").unwrap().concretize(), Ok(Pon::Boolean(true)));
+    assert_eq!(Pon::from_string("'a' >= 'b'").unwrap().concretize(), Ok(Pon::Boolean(false)));
+    assert_eq!(Pon::from_string("[1] != [1]").unwrap().concretize(), Ok(Pon::Boolean(false)));
+}
+
+#[test]
+fn test_evaluate_vectors() {
+    let pon = Pon::from_string("vec3 { x: 1.0, y: 2.0, z: 3.0 } * 2.0 + #vec3[1.0, 1.0, 1.0]").unwrap();
+    assert_eq!(pon.concretize(), Ok(Pon::Vector3(Vector3::new(3.0, 5.0, 7.0))));
+    let pon = Pon::from_string("-#vec4[1.0, 0.0, 0.0, 1.0]").unwrap();
+    assert_eq!(pon.concretize(), Ok(Pon::Vector4(Vector4::new(-1.0, 0.0, 0.0, -1.0))));
+}
+
+#[test]
+fn test_evaluate_matrix() {
+    let pon = Pon::from_string("translate { x: 1.0, y: 0.0, z: 0.0 } * #vec4[0.0, 0.0, 0.0, 1.0]").unwrap();
+    assert_eq!(pon.concretize(), Ok(Pon::Vector4(Vector4::new(1.0, 0.0, 0.0, 1.0))));
+}
+
+#[test]
+fn test_evaluate_translate() {
+    let pon = Pon::from_string("2.0 * 3").unwrap();
+    let v: f32 = pon.translate(&mut TranslateContext::empty()).unwrap();
+    assert_eq!(v, 6.0);
+}
@@ -0,0 +1,275 @@
+use pon::*;
+use cgmath::*;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Once, ONCE_INIT};
+
+// A pure function callable from pon with the call syntax, e.g. `max(@this.x, 0.0)`. A call with a
+// single argument passes that argument concretized, otherwise the function receives an array of
+// the arguments, so `max(1, 2)` and `max([1, 2])` are the same call.
+pub type PonFunction = Box<Fn(&Pon) -> Result<Pon, PonTranslateErr> + Send + Sync>;
+
+// Shared by all threads, so functions registered at startup can be used by documents loaded on
+// other threads
+fn functions() -> &'static Mutex<HashMap<String, Arc<PonFunction>>> {
+    static INIT: Once = ONCE_INIT;
+    static mut FUNCTIONS: *const Mutex<HashMap<String, Arc<PonFunction>>> = 0 as *const _;
+    unsafe {
+        INIT.call_once(|| {
+            FUNCTIONS = Box::into_raw(Box::new(Mutex::new(builtin_functions())));
+        });
+        &*FUNCTIONS
+    }
+}
+
+fn builtin_functions() -> HashMap<String, Arc<PonFunction>> {
+    let builtins: [(&str, fn(&Pon) -> Result<Pon, PonTranslateErr>); 11] = [
+        ("min", min), ("max", max), ("clamp", clamp), ("lerp", lerp), ("sin", sin), ("cos", cos),
+        ("length", length), ("normalize", normalize), ("concat", concat), ("len", len), ("format", format)
+    ];
+    let mut functions = HashMap::new();
+    for &(name, function) in builtins.iter() {
+        functions.insert(name.to_string(), Arc::new(Box::new(function) as PonFunction));
+    }
+    functions
+}
+
+// Registers a function for all threads, replacing any function with the same name.
+pub fn register_pon_function(name: &str, function: PonFunction) {
+    functions().lock().unwrap().insert(name.to_string(), Arc::new(function));
+}
+
+pub fn has_pon_function(name: &str) -> bool {
+    functions().lock().unwrap().contains_key(name)
+}
+
+// Returns None if there's no function with that name. The lock isn't held during the call, so
+// functions can call other functions.
+pub fn call_pon_function(name: &str, data: &Pon) -> Option<Result<Pon, PonTranslateErr>> {
+    let function = functions().lock().unwrap().get(name).cloned();
+    function.map(|function| {
+        let args = try!(data.concretize());
+        (**function)(&args)
+    })
+}
+
+fn arguments(data: &Pon, count: usize) -> Result<&Vec<Pon>, PonTranslateErr> {
+    match data {
+        &Pon::Array(ref args) if args.len() == count => Ok(args),
+        _ => Err(PonTranslateErr::MismatchType { expected: format!("Array of {} arguments", count), found: data.to_string() })
+    }
+}
+
+fn binary(op: BinaryOperator, a: &Pon, b: &Pon) -> Result<Pon, PonTranslateErr> {
+    PonExpression::Binary(op, a.clone(), b.clone()).evaluate()
+}
+
+fn pick(data: &Pon, op: BinaryOperator) -> Result<Pon, PonTranslateErr> {
+    data.as_array(|args| {
+        let mut iter = args.iter();
+        let mut best = match iter.next() {
+            Some(first) => first.clone(),
+            None => return Err(PonTranslateErr::InvalidValue { value: data.to_string() })
+        };
+        for v in iter {
+            if try!(binary(op, v, &best)) == Pon::Boolean(true) {
+                best = v.clone();
+            }
+        }
+        Ok(best)
+    })
+}
+
+fn min(data: &Pon) -> Result<Pon, PonTranslateErr> {
+    pick(data, BinaryOperator::Less)
+}
+
+fn max(data: &Pon) -> Result<Pon, PonTranslateErr> {
+    pick(data, BinaryOperator::Greater)
+}
+
+// clamp [value, min, max]
+fn clamp(data: &Pon) -> Result<Pon, PonTranslateErr> {
+    let args = try!(arguments(data, 3));
+    let v = try!(min(&Pon::Array(vec![args[0].clone(), args[2].clone()])));
+    max(&Pon::Array(vec![v, args[1].clone()]))
+}
+
+// lerp [a, b, t], works on anything that supports + - and * with a float, so vectors too
+fn lerp(data: &Pon) -> Result<Pon, PonTranslateErr> {
+    let args = try!(arguments(data, 3));
+    let delta = try!(binary(BinaryOperator::Sub, &args[1], &args[0]));
+    let delta = try!(binary(BinaryOperator::Mul, &delta, &args[2]));
+    binary(BinaryOperator::Add, &args[0], &delta)
+}
+
+fn sin(data: &Pon) -> Result<Pon, PonTranslateErr> {
+    let v: f32 = try!(data.translate(&mut TranslateContext::empty()));
+    Ok(Pon::Float(v.sin()))
+}
+
+fn cos(data: &Pon) -> Result<Pon, PonTranslateErr> {
+    let v: f32 = try!(data.translate(&mut TranslateContext::empty()));
+    Ok(Pon::Float(v.cos()))
+}
+
+fn vector(data: &Pon) -> Result<Pon, PonTranslateErr> {
+    let mut context = TranslateContext::empty();
+    match data {
+        &Pon::Vector4(_) => Ok(Pon::Vector4(try!(data.translate(&mut context)))),
+        &Pon::TypedPon(box TypedPon { ref type_name, .. }) if type_name == "vec4" =>
+            Ok(Pon::Vector4(try!(data.translate(&mut context)))),
+        _ => Ok(Pon::Vector3(try!(data.translate(&mut context))))
+    }
+}
+
+fn length(data: &Pon) -> Result<Pon, PonTranslateErr> {
+    match try!(vector(data)) {
+        Pon::Vector3(v) => Ok(Pon::Float(v.length())),
+        Pon::Vector4(v) => Ok(Pon::Float(v.length())),
+        _ => unreachable!()
+    }
+}
+
+fn normalize(data: &Pon) -> Result<Pon, PonTranslateErr> {
+    match try!(vector(data)) {
+        Pon::Vector3(v) => Ok(Pon::Vector3(v.normalize())),
+        Pon::Vector4(v) => Ok(Pon::Vector4(v.normalize())),
+        _ => unreachable!()
+    }
+}
+
+// Joins strings or arrays
+fn concat(data: &Pon) -> Result<Pon, PonTranslateErr> {
+    data.as_array(|args| {
+        if args.iter().all(|v| match v { &Pon::String(_) => true, _ => false }) {
+            let mut out = String::new();
+            for v in args {
+                if let &Pon::String(ref s) = v {
+                    out.push_str(s);
+                }
+            }
+            return Ok(Pon::String(out));
+        }
+        let mut out = vec![];
+        for v in args {
+            match v {
+                &Pon::Array(ref arr) => out.extend(arr.iter().cloned()),
+                _ => return Err(PonTranslateErr::MismatchType { expected: "Array of strings or arrays".to_string(), found: data.to_string() })
+            }
+        }
+        Ok(Pon::Array(out))
+    })
+}
+
+fn len(data: &Pon) -> Result<Pon, PonTranslateErr> {
+    let len = match data {
+        &Pon::String(ref v) => v.chars().count(),
+        &Pon::Array(ref v) => v.len(),
+        &Pon::FloatArray(ref v) => v.len(),
+        &Pon::IntegerArray(ref v) => v.len(),
+        &Pon::Object(ref v) => v.len(),
+        _ => return Err(PonTranslateErr::MismatchType { expected: "String, array or object".to_string(), found: data.to_string() })
+    };
+    Ok(Pon::Integer(len as i64))
+}
+
+// format ['{} of {}', a, b], each {} is replaced by the next argument. Strings are inserted as is,
+// anything else the way it's written in pon.
+fn format(data: &Pon) -> Result<Pon, PonTranslateErr> {
+    data.as_array(|args| {
+        let template = match args.first() {
+            Some(&Pon::String(ref template)) => template,
+            _ => return Err(PonTranslateErr::MismatchType { expected: "Format string as first argument".to_string(), found: data.to_string() })
+        };
+        let mut values = args[1..].iter();
+        let mut out = String::new();
+        let mut parts = template.split("{}");
+        if let Some(part) = parts.next() {
+            out.push_str(part);
+        }
+        for part in parts {
+            match values.next() {
+                Some(&Pon::String(ref v)) => out.push_str(v),
+                Some(v) => out.push_str(&v.to_string()),
+                None => return Err(PonTranslateErr::InvalidValue { value: format!("not enough arguments for '{}'", template) })
+            }
+            out.push_str(part);
+        }
+        Ok(Pon::String(out))
+    })
+}
+
+
+#[test]
+fn test_min_max() {
+    assert_eq!(Pon::from_string("min(3, 1.5, 2)").unwrap().concretize(), Ok(Pon::Float(1.5)));
+    assert_eq!(Pon::from_string("max([3, 1.5, 2])").unwrap().concretize(), Ok(Pon::Integer(3)));
+    assert!(Pon::from_string("max([])").unwrap().concretize().is_err());
+}
+
+#[test]
+fn test_clamp_lerp() {
+    assert_eq!(Pon::from_string("clamp(5.0, 0.0, 1.0)").unwrap().concretize(), Ok(Pon::Float(1.0)));
+    assert_eq!(Pon::from_string("lerp(0.0, 10.0, 0.25)").unwrap().concretize(), Ok(Pon::Float(2.5)));
+    assert_eq!(Pon::from_string("lerp(#vec3[0.0, 0.0, 0.0], #vec3[2.0, 4.0, 8.0], 0.5)").unwrap().concretize(),
+        Ok(Pon::Vector3(Vector3::new(1.0, 2.0, 4.0))));
+}
+
+#[test]
+fn test_trigonometry() {
+    assert_eq!(Pon::from_string("sin(0.0)").unwrap().concretize(), Ok(Pon::Float(0.0)));
+    assert_eq!(Pon::from_string("cos(0)").unwrap().concretize(), Ok(Pon::Float(1.0)));
+}
+
+#[test]
+fn test_length_normalize() {
+    assert_eq!(Pon::from_string("length(vec3 { x: 3.0, y: 4.0 })").unwrap().concretize(), Ok(Pon::Float(5.0)));
+    assert_eq!(Pon::from_string("normalize(#vec4[0.0, 2.0, 0.0, 0.0])").unwrap().concretize(),
+        Ok(Pon::Vector4(Vector4::new(0.0, 1.0, 0.0, 0.0))));
+}
+
+#[test]
+fn test_concat_len() {
+    assert_eq!(Pon::from_string("concat('a', 'b')").unwrap().concretize(), Ok(Pon::String("ab".to_string())));
+    assert_eq!(Pon::from_string("concat([1], [2, 3])").unwrap().concretize(),
+        Ok(Pon::Array(vec![Pon::Integer(1), Pon::Integer(2), Pon::Integer(3)])));
+    assert_eq!(Pon::from_string("len('hello')").unwrap().concretize(), Ok(Pon::Integer(5)));
+    assert_eq!(Pon::from_string("len(#f32[1.0, 2.0])").unwrap().concretize(), Ok(Pon::Integer(2)));
+}
+
+#[test]
+fn test_format() {
+    assert_eq!(Pon::from_string("format('{} has {} hp', 'player', 10)").unwrap().concretize(),
+        Ok(Pon::String("player has 10 hp".to_string())));
+    assert!(Pon::from_string("format('{} {}', 1)").unwrap().concretize().is_err());
+}
+
+#[test]
+fn test_function_nested() {
+    assert_eq!(Pon::from_string("max(len('abc'), 1) * 2").unwrap().concretize(), Ok(Pon::Integer(6)));
+}
+
+#[test]
+fn test_typed_pon_is_not_a_call() {
+    let v = Pon::from_string("normalize { x: 2.0 }").unwrap();
+    assert_eq!(v.concretize(), Ok(v.clone()));
+    assert!(Pon::from_string("no_such_function(1)").unwrap().concretize().is_err());
+}
+
+#[test]
+fn test_register_function() {
+    let factor = 2;
+    assert_eq!(has_pon_function("multiply"), false);
+    register_pon_function("multiply", Box::new(move |data: &Pon| binary(BinaryOperator::Mul, data, &Pon::Integer(factor))));
+    assert_eq!(Pon::from_string("multiply(21)").unwrap().concretize(), Ok(Pon::Integer(42)));
+    let v: i64 = Pon::from_string("multiply(4)").unwrap().translate(&mut TranslateContext::empty()).unwrap();
+    assert_eq!(v, 8);
+}
+
+#[test]
+fn test_register_function_on_other_thread() {
+    ::std::thread::spawn(|| {
+        register_pon_function("negate", Box::new(|data: &Pon| PonExpression::Unary(UnaryOperator::Neg, data.clone()).evaluate()));
+    }).join().unwrap();
+    assert_eq!(Pon::from_string("negate(3)").unwrap().concretize(), Ok(Pon::Integer(-3)));
+}
@@ -0,0 +1,355 @@
+// JSON import and export, enabled with the `json` feature.
+//
+// Pon values map to JSON like this:
+//
+//   ()                        null
+//   true, 5, 5.0, 'text'      true, 5, 5.0, "text"
+//   [a, b]                    [a, b]
+//   { key: a }                { "key": a }, keys starting with $ get an extra $ in front
+//   name data                 { "$type": "name", "$data": data }
+//   @this.x                   { "$ref": "this.x" }
+//   this.x                    { "$path": "this.x" }
+//   #f32[..], #i64[..]        { "$f32": [..] }, { "$i64": [..] }
+//   #vec3, #vec4, #mat4       { "$vec3": [..] }, { "$vec4": [..] }, { "$mat4": [..] }
+//   @this.x * 2, `${..}`      { "$expr": "@this.x * 2" }, expressions are kept as pon text
+//   nan, inf, -inf            { "$float": "nan" }, since JSON has no such numbers
+//
+// Documents are written as { "root": entity } where each entity is
+// { "type_name": .., "name": .., "properties": { .. }, "children": [ .. ] }, with name left out for
+// unnamed entities and the property values mapped as above.
+
+use pon::*;
+use document::*;
+use serde_json::{self, Value, Map, Number};
+use cgmath::{Vector3, Vector4, Matrix4};
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct PonJsonError(pub String);
+
+impl ToString for PonJsonError {
+    fn to_string(&self) -> String {
+        self.0.clone()
+    }
+}
+
+impl From<PonJsonError> for DocError {
+    fn from(err: PonJsonError) -> DocError {
+        DocError::Json(err.0)
+    }
+}
+
+impl Pon {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self.to_json_value()).unwrap()
+    }
+    pub fn from_json(json: &str) -> Result<Pon, PonJsonError> {
+        match serde_json::from_str(json) {
+            Ok(value) => Pon::from_json_value(&value),
+            Err(err) => Err(PonJsonError(err.to_string()))
+        }
+    }
+    pub fn to_json_value(&self) -> Value {
+        match self {
+            &Pon::Nil => Value::Null,
+            &Pon::Boolean(v) => Value::Bool(v),
+            &Pon::Integer(v) => Value::from(v),
+            &Pon::Float(v) => float_to_json(v),
+            &Pon::String(ref v) => Value::String(v.clone()),
+            &Pon::Array(ref array) => Value::Array(array.iter().map(|v| v.to_json_value()).collect()),
+            &Pon::Object(ref hm) => {
+                let mut map = Map::new();
+                for (key, value) in hm {
+                    let key = if key.starts_with("$") { format!("${}", key) } else { key.clone() };
+                    map.insert(key, value.to_json_value());
+                }
+                Value::Object(map)
+            },
+            &Pon::TypedPon(box TypedPon { ref type_name, ref data }) => {
+                let mut map = Map::new();
+                map.insert("$type".to_string(), Value::String(type_name.clone()));
+                map.insert("$data".to_string(), data.to_json_value());
+                Value::Object(map)
+            },
+            &Pon::DependencyReference(ref named_prop_ref, _) => tagged("$ref", Value::String(named_prop_ref.to_string())),
+            &Pon::Reference(ref named_prop_ref) => tagged("$path", Value::String(named_prop_ref.to_string())),
+            &Pon::FloatArray(ref v) => tagged("$f32", floats_to_json(v)),
+            &Pon::IntegerArray(ref v) => tagged("$i64", Value::Array(v.iter().map(|v| Value::from(*v)).collect())),
+            &Pon::Vector3(ref v) => tagged("$vec3", floats_to_json(&[v.x, v.y, v.z])),
+            &Pon::Vector4(ref v) => tagged("$vec4", floats_to_json(&[v.x, v.y, v.z, v.w])),
+            &Pon::Matrix4(ref v) => tagged("$mat4", floats_to_json(&[
+                    v.x.x, v.x.y, v.x.z, v.x.w,
+                    v.y.x, v.y.y, v.y.z, v.y.w,
+                    v.z.x, v.z.y, v.z.z, v.z.w,
+                    v.w.x, v.w.y, v.w.z, v.w.w
+                ])),
+            &Pon::Expression(..) => tagged("$expr", Value::String(self.to_string()))
+        }
+    }
+    pub fn from_json_value(value: &Value) -> Result<Pon, PonJsonError> {
+        match value {
+            &Value::Null => Ok(Pon::Nil),
+            &Value::Bool(v) => Ok(Pon::Boolean(v)),
+            &Value::Number(ref v) => match v.as_i64() {
+                Some(v) => Ok(Pon::Integer(v)),
+                // integers above i64::MAX would lose precision as floats
+                None if v.is_u64() => Err(PonJsonError(format!("Integer {} is out of range", v))),
+                None => Ok(Pon::Float(try!(json_to_float(value))))
+            },
+            &Value::String(ref v) => Ok(Pon::String(v.clone())),
+            &Value::Array(ref array) => {
+                let mut out = vec![];
+                for v in array {
+                    out.push(try!(Pon::from_json_value(v)));
+                }
+                Ok(Pon::Array(out))
+            },
+            &Value::Object(ref map) => {
+                if map.keys().any(|key| key.starts_with("$") && !key.starts_with("$$")) {
+                    return tagged_from_json(map);
+                }
+                let mut object = PonObject::new();
+                for (key, value) in map {
+                    let key = if key.starts_with("$$") { key[1..].to_string() } else { key.clone() };
+                    object.insert(key, try!(Pon::from_json_value(value)));
+                }
+                Ok(Pon::Object(object))
+            }
+        }
+    }
+}
+
+fn tagged(tag: &str, value: Value) -> Value {
+    let mut map = Map::new();
+    map.insert(tag.to_string(), value);
+    Value::Object(map)
+}
+
+fn tagged_from_json(map: &Map<String, Value>) -> Result<Pon, PonJsonError> {
+    if map.len() == 2 {
+        if let (Some(&Value::String(ref type_name)), Some(data)) = (map.get("$type"), map.get("$data")) {
+            return Ok(Pon::new_typed_pon(type_name, try!(Pon::from_json_value(data))));
+        }
+    }
+    let (tag, value) = match map.iter().next() {
+        Some(entry) if map.len() == 1 => entry,
+        _ => return Err(PonJsonError(format!("Expected a single tag, found {}", Value::Object(map.clone()))))
+    };
+    match &tag[..] {
+        "$ref" => Ok(Pon::DependencyReference(try!(named_prop_ref_from_json(value)), None)),
+        "$path" => Ok(Pon::Reference(try!(named_prop_ref_from_json(value)))),
+        "$f32" => Ok(Pon::FloatArray(try!(json_to_floats(value, None)))),
+        "$i64" => match value {
+            &Value::Array(ref array) => {
+                let mut out = vec![];
+                for v in array {
+                    match v.as_i64() {
+                        Some(v) => out.push(v),
+                        None => return Err(PonJsonError(format!("Expected an integer, found {}", v)))
+                    }
+                }
+                Ok(Pon::IntegerArray(out))
+            },
+            _ => Err(PonJsonError(format!("Expected an array, found {}", value)))
+        },
+        "$vec3" => {
+            let v = try!(json_to_floats(value, Some(3)));
+            Ok(Pon::Vector3(Vector3::new(v[0], v[1], v[2])))
+        },
+        "$vec4" => {
+            let v = try!(json_to_floats(value, Some(4)));
+            Ok(Pon::Vector4(Vector4::new(v[0], v[1], v[2], v[3])))
+        },
+        "$mat4" => {
+            let v = try!(json_to_floats(value, Some(16)));
+            Ok(Pon::Matrix4(Matrix4::new(
+                v[0], v[1], v[2], v[3],
+                v[4], v[5], v[6], v[7],
+                v[8], v[9], v[10], v[11],
+                v[12], v[13], v[14], v[15])))
+        },
+        "$expr" => match value {
+            &Value::String(ref v) => Pon::from_string(v).map_err(|err| PonJsonError(err.to_string())),
+            _ => Err(PonJsonError(format!("Expected pon text, found {}", value)))
+        },
+        "$float" => Ok(Pon::Float(try!(json_to_float(&tagged(tag, value.clone()))))),
+        _ => Err(PonJsonError(format!("Unknown tag {}", tag)))
+    }
+}
+
+fn named_prop_ref_from_json(value: &Value) -> Result<NamedPropRef, PonJsonError> {
+    match value {
+        &Value::String(ref v) => NamedPropRef::from_string(v).map_err(|err| PonJsonError(err.to_string())),
+        _ => Err(PonJsonError(format!("Expected a reference, found {}", value)))
+    }
+}
+
+fn float_to_json(v: f32) -> Value {
+    if v.is_nan() {
+        tagged("$float", Value::String("nan".to_string()))
+    } else if v.is_infinite() {
+        tagged("$float", Value::String(if v < 0.0 { "-inf" } else { "inf" }.to_string()))
+    } else {
+        // Going through the shortest f32 representation, so 0.1 is written as 0.1 and not as the
+        // f64 closest to the f32 closest to 0.1
+        let v: f64 = v.to_string().parse().unwrap();
+        Value::Number(Number::from_f64(v).unwrap())
+    }
+}
+
+fn floats_to_json(v: &[f32]) -> Value {
+    Value::Array(v.iter().map(|v| float_to_json(*v)).collect())
+}
+
+fn json_to_float(value: &Value) -> Result<f32, PonJsonError> {
+    match value {
+        &Value::Number(ref v) => Ok(v.as_f64().unwrap() as f32),
+        &Value::Object(ref map) => match map.get("$float") {
+            Some(&Value::String(ref v)) if map.len() == 1 => match &v[..] {
+                "nan" => Ok(::std::f32::NAN),
+                "inf" => Ok(::std::f32::INFINITY),
+                "-inf" => Ok(::std::f32::NEG_INFINITY),
+                _ => Err(PonJsonError(format!("Expected nan, inf or -inf, found {}", v)))
+            },
+            _ => Err(PonJsonError(format!("Expected a float, found {}", value)))
+        },
+        _ => Err(PonJsonError(format!("Expected a float, found {}", value)))
+    }
+}
+
+fn json_to_floats(value: &Value, len: Option<usize>) -> Result<Vec<f32>, PonJsonError> {
+    match value {
+        &Value::Array(ref array) if len.map(|len| len == array.len()).unwrap_or(true) => {
+            let mut out = vec![];
+            for v in array {
+                out.push(try!(json_to_float(v)));
+            }
+            Ok(out)
+        },
+        _ => Err(PonJsonError(format!("Expected an array of {} floats, found {}",
+            len.map(|len| len.to_string()).unwrap_or("any number of".to_string()), value)))
+    }
+}
+
+impl Document {
+    pub fn to_json(&self) -> String {
+        let root = match self.snapshot().root {
+            Some(ref root) => entity_to_json(root),
+            None => Value::Null
+        };
+        serde_json::to_string_pretty(&tagged("root", root)).unwrap()
+    }
+    pub fn from_json(json: &str) -> Result<Document, DocError> {
+        Document::from_json_with_report(json, &DocumentLoadOptions::default()).map(|(doc, _)| doc)
+    }
+    // Unresolved properties are reported the same way as for snapshots
+    pub fn from_json_with_report(json: &str, options: &DocumentLoadOptions) -> Result<(Document, DocumentLoadReport), DocError> {
+        let value: Value = match serde_json::from_str(json) {
+            Ok(value) => value,
+            Err(err) => return Err(DocError::Json(err.to_string()))
+        };
+        let root = match value.get("root") {
+            Some(&Value::Null) | None => None,
+            Some(root) => Some(try!(entity_from_json(root)))
+        };
+        Document::from_snapshot_with_report(&DocumentSnapshot { root: root }, options)
+    }
+}
+
+fn entity_to_json(entity: &EntitySnapshot) -> Value {
+    let mut map = Map::new();
+    map.insert("type_name".to_string(), Value::String(entity.type_name.clone()));
+    if let Some(ref name) = entity.name {
+        map.insert("name".to_string(), Value::String(name.clone()));
+    }
+    let mut properties = Map::new();
+    for &(ref key, ref value) in &entity.properties {
+        properties.insert(key.clone(), value.to_json_value());
+    }
+    map.insert("properties".to_string(), Value::Object(properties));
+    map.insert("children".to_string(), Value::Array(entity.children.iter().map(entity_to_json).collect()));
+    Value::Object(map)
+}
+
+fn entity_from_json(value: &Value) -> Result<EntitySnapshot, PonJsonError> {
+    let type_name = match value.get("type_name") {
+        Some(&Value::String(ref type_name)) => type_name.clone(),
+        _ => return Err(PonJsonError(format!("Expected an entity with a type_name, found {}", value)))
+    };
+    let name = match value.get("name") {
+        Some(&Value::String(ref name)) => Some(name.clone()),
+        Some(&Value::Null) | None => None,
+        Some(other) => return Err(PonJsonError(format!("Expected a name, found {}", other)))
+    };
+    let mut properties = vec![];
+    match value.get("properties") {
+        Some(&Value::Object(ref map)) => {
+            for (key, value) in map {
+                properties.push((key.clone(), try!(Pon::from_json_value(value))));
+            }
+        },
+        None => {},
+        Some(other) => return Err(PonJsonError(format!("Expected properties, found {}", other)))
+    }
+    properties.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut children = vec![];
+    match value.get("children") {
+        Some(&Value::Array(ref array)) => {
+            for child in array {
+                children.push(try!(entity_from_json(child)));
+            }
+        },
+        None => {},
+        Some(other) => return Err(PonJsonError(format!("Expected children, found {}", other)))
+    }
+    Ok(EntitySnapshot {
+        type_name: type_name,
+        name: name,
+        properties: properties,
+        children: children
+    })
+}
+
+
+#[test]
+fn test_json_mapping() {
+    let pon = Pon::from_string("{ a: [1, 2.5, 'x', (), true], '$b': @this.x, c: vec3 { x: 1.0 }, d: #vec3[1.0, 0.1, nan], e: this.y * 2 }").unwrap();
+    assert_eq!(pon.to_json(), r#"{"a":[1,2.5,"x",null,true],"$$b":{"$ref":"this.x"},"c":{"$type":"vec3","$data":{"x":1.0}},"d":{"$vec3":[1.0,0.1,{"$float":"nan"}]},"e":{"$expr":"this.y * 2"}}"#);
+}
+
+#[test]
+fn test_json_round_trip() {
+    let pon = Pon::from_string("{ a: [1, 2.5, -inf], '$b': parent:cam.fov, c: rotate_x 0.5, d: #i64[1, 2], e: `${@this.x}`, f: #mat4[1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0] }").unwrap();
+    assert_eq!(Pon::from_json(&pon.to_json()), Ok(pon));
+}
+
+#[test]
+fn test_json_errors() {
+    assert!(Pon::from_json("{ \"$vec3\": [1.0] }").is_err());
+    assert!(Pon::from_json("{ \"$unknown\": 1 }").is_err());
+    assert!(Pon::from_json("{ \"$ref\": \"not a reference\" }").is_err());
+    assert!(Pon::from_json("[1,").is_err());
+    assert_eq!(Pon::from_json("9223372036854775807"), Ok(Pon::Integer(::std::i64::MAX)));
+    assert!(Pon::from_json("9223372036854775808").is_err());
+    assert_eq!(Pon::from_json("-1.5e3"), Ok(Pon::Float(-1500.0)));
+}
+
+#[test]
+fn test_document_json_round_trip() {
+    let doc = Document::from_string(r#"<Entity name="root" x="5.0"><Sprite y="@root.x + 1" z="{ a: @root.x }" /><Entity name="other" /></Entity>"#).unwrap();
+    let json = doc.to_json();
+    let copy = Document::from_json(&json).unwrap();
+    assert_eq!(copy.snapshot(), doc.snapshot());
+    let xml = Document::from_string(&copy.to_string()).unwrap();
+    assert_eq!(xml.snapshot(), doc.snapshot());
+}
+
+#[test]
+fn test_document_json_errors() {
+    assert!(Document::from_json("{ \"root\": { \"name\": \"x\" } }").is_err());
+    assert_eq!(Document::from_json("{ \"root\": null }").unwrap().get_root(), None);
+    let json = r#"{ "root": { "type_name": "Entity", "properties": { "y": { "$ref": "what.x" } }, "children": [] } }"#;
+    let (_, report) = Document::from_json_with_report(json, &DocumentLoadOptions::default()).unwrap();
+    assert_eq!(report.diagnostics.len(), 1);
+    assert_eq!(report.diagnostics[0].kind, LoadDiagnosticKind::UnresolvedProperty);
+    assert!(Document::from_json_with_report(json, &DocumentLoadOptions { strict: true }).is_err());
+}
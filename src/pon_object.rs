@@ -0,0 +1,208 @@
+use pon::*;
+use std::collections::HashMap;
+use std::iter::FromIterator;
+use std::slice;
+use std::vec;
+use std::fmt;
+
+// The map behind Pon::Object. Keys keep the order they were inserted in, so objects are written
+// back out in the order they were read. Equality doesn't depend on the order, same as for a map.
+// Comments written in front of a key are kept too, they don't take part in equality either.
+#[derive(Clone)]
+pub struct PonObject {
+    entries: Vec<(String, Pon)>,
+    index: HashMap<String, usize>,
+    comments: HashMap<String, Vec<String>>
+}
+
+impl PonObject {
+    pub fn new() -> PonObject {
+        PonObject {
+            entries: vec![],
+            index: HashMap::new(),
+            comments: HashMap::new()
+        }
+    }
+    // The comments in front of key, as written including the // or /* */
+    pub fn comments(&self, key: &str) -> &[String] {
+        match self.comments.get(key) {
+            Some(comments) => comments,
+            None => &[]
+        }
+    }
+    pub fn set_comments(&mut self, key: &str, comments: Vec<String>) {
+        if comments.len() > 0 {
+            self.comments.insert(key.to_string(), comments);
+        } else {
+            self.comments.remove(key);
+        }
+    }
+    // Replacing the value of an existing key keeps the key where it was
+    pub fn insert(&mut self, key: String, value: Pon) -> Option<Pon> {
+        match self.index.get(&key) {
+            Some(&i) => return Some(::std::mem::replace(&mut self.entries[i].1, value)),
+            None => {}
+        }
+        self.index.insert(key.clone(), self.entries.len());
+        self.entries.push((key, value));
+        None
+    }
+    pub fn remove(&mut self, key: &str) -> Option<Pon> {
+        self.comments.remove(key);
+        match self.index.remove(key) {
+            Some(i) => {
+                let (_, value) = self.entries.remove(i);
+                for &(ref key, _) in &self.entries[i..] {
+                    *self.index.get_mut(key).unwrap() -= 1;
+                }
+                Some(value)
+            },
+            None => None
+        }
+    }
+    pub fn get(&self, key: &str) -> Option<&Pon> {
+        match self.index.get(key) {
+            Some(&i) => Some(&self.entries[i].1),
+            None => None
+        }
+    }
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut Pon> {
+        match self.index.get(key) {
+            Some(&i) => Some(&mut self.entries[i].1),
+            None => None
+        }
+    }
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.index.contains_key(key)
+    }
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    pub fn iter(&self) -> PonObjectIter {
+        PonObjectIter(self.entries.iter())
+    }
+    pub fn iter_mut(&mut self) -> PonObjectIterMut {
+        PonObjectIterMut(self.entries.iter_mut())
+    }
+    pub fn keys<'a>(&'a self) -> Vec<&'a String> {
+        self.entries.iter().map(|&(ref key, _)| key).collect()
+    }
+}
+
+impl PartialEq for PonObject {
+    fn eq(&self, other: &PonObject) -> bool {
+        self.len() == other.len() && self.iter().all(|(key, value)| other.get(key) == Some(value))
+    }
+}
+
+// Pon hashes its debug representation, so it's written sorted by key to agree with equality
+impl fmt::Debug for PonObject {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut entries: Vec<&(String, Pon)> = self.entries.iter().collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        try!(write!(f, "{{"));
+        for (i, &&(ref key, ref value)) in entries.iter().enumerate() {
+            if i > 0 {
+                try!(write!(f, ", "));
+            }
+            try!(write!(f, "{:?}: {:?}", key, value));
+        }
+        write!(f, "}}")
+    }
+}
+
+pub struct PonObjectIter<'a>(slice::Iter<'a, (String, Pon)>);
+
+impl<'a> Iterator for PonObjectIter<'a> {
+    type Item = (&'a String, &'a Pon);
+    fn next(&mut self) -> Option<(&'a String, &'a Pon)> {
+        self.0.next().map(|&(ref key, ref value)| (key, value))
+    }
+}
+
+pub struct PonObjectIterMut<'a>(slice::IterMut<'a, (String, Pon)>);
+
+impl<'a> Iterator for PonObjectIterMut<'a> {
+    type Item = (&'a String, &'a mut Pon);
+    fn next(&mut self) -> Option<(&'a String, &'a mut Pon)> {
+        self.0.next().map(|&mut (ref key, ref mut value)| (key, value))
+    }
+}
+
+impl<'a> IntoIterator for &'a PonObject {
+    type Item = (&'a String, &'a Pon);
+    type IntoIter = PonObjectIter<'a>;
+    fn into_iter(self) -> PonObjectIter<'a> {
+        self.iter()
+    }
+}
+
+impl IntoIterator for PonObject {
+    type Item = (String, Pon);
+    type IntoIter = vec::IntoIter<(String, Pon)>;
+    fn into_iter(self) -> vec::IntoIter<(String, Pon)> {
+        self.entries.into_iter()
+    }
+}
+
+impl FromIterator<(String, Pon)> for PonObject {
+    fn from_iter<I: IntoIterator<Item=(String, Pon)>>(iter: I) -> PonObject {
+        let mut object = PonObject::new();
+        for (key, value) in iter {
+            object.insert(key, value);
+        }
+        object
+    }
+}
+
+
+#[test]
+fn test_insertion_order() {
+    let object = Pon::from_string("{ z: 1, a: 2, m: 3 }").unwrap();
+    assert_eq!(object.to_string(), "{ z: 1, a: 2, m: 3 }");
+}
+
+#[test]
+fn test_insert_existing_keeps_position() {
+    let mut object = hashmap!{ "a" => Pon::Integer(1), "b" => Pon::Integer(2) };
+    assert_eq!(object.insert("a".to_string(), Pon::Integer(3)), Some(Pon::Integer(1)));
+    assert_eq!(object.keys(), vec!["a", "b"]);
+}
+
+#[test]
+fn test_remove() {
+    let mut object = hashmap!{ "a" => Pon::Integer(1), "b" => Pon::Integer(2), "c" => Pon::Integer(3) };
+    assert_eq!(object.remove("a"), Some(Pon::Integer(1)));
+    assert_eq!(object.get("c"), Some(&Pon::Integer(3)));
+    assert_eq!(object.keys(), vec!["b", "c"]);
+}
+
+#[test]
+fn test_equality_ignores_order() {
+    let a = Pon::from_string("{ x: 1, y: 2 }").unwrap();
+    let b = Pon::from_string("{ y: 2, x: 1 }").unwrap();
+    assert_eq!(a, b);
+    assert_eq!(format!("{:?}", a), format!("{:?}", b));
+    assert!(a != Pon::from_string("{ x: 1 }").unwrap());
+}
+
+#[test]
+fn test_comments() {
+    let object = Pon::from_string("{\n  // the x\n  x: 1, /* y */ y: 2,\n  z: 3\n}").unwrap();
+    match &object {
+        &Pon::Object(ref hm) => {
+            assert_eq!(hm.comments("x"), &["// the x".to_string()][..]);
+            assert_eq!(hm.comments("y"), &["/* y */".to_string()][..]);
+            assert_eq!(hm.comments("z").len(), 0);
+        },
+        _ => panic!("expected an object")
+    }
+    assert_eq!(object.to_string(), "{ x: 1, y: 2, z: 3 }");
+    let options = PonStringifyOptions { keep_comments: true, ..PonStringifyOptions::default() };
+    let text = object.to_string_with(&options);
+    assert_eq!(text, "{\n  // the x\n  x: 1,\n  /* y */\n  y: 2,\n  z: 3\n}");
+    assert_eq!(Pon::from_string(&text).unwrap().to_string_with(&options), text);
+}
@@ -0,0 +1,192 @@
+use pon::*;
+use std::mem;
+use cgmath::{Vector3, Vector4, Matrix4};
+
+// Small deterministic xorshift generator, so the round trip can be checked against lots of
+// generated values without pulling in a property testing crate
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+    fn float(&mut self) -> f32 {
+        loop {
+            let v: f32 = unsafe { mem::transmute(self.next() as u32) };
+            // nan never compares equal, so it can't be checked with a round trip
+            if !v.is_nan() {
+                return v;
+            }
+        }
+    }
+    fn pick(&mut self, items: &[&'static str]) -> String {
+        items[self.below(items.len() as u64) as usize].to_string()
+    }
+}
+
+// Used for entity, property, type and function names. Includes names that are keywords or numbers
+// elsewhere, which must still be read back as names.
+const NAMES: &'static [&'static str] = &["a", "b", "player", "cam_1", "_x", "Entity2", "inf", "nan", "if", "then", "else",
+    "parent_panel", "this_one", "true_color"];
+const OPERATORS: &'static [BinaryOperator] = &[BinaryOperator::Add, BinaryOperator::Sub,
+    BinaryOperator::Mul, BinaryOperator::Div, BinaryOperator::Rem, BinaryOperator::Equal,
+    BinaryOperator::NotEqual, BinaryOperator::Less, BinaryOperator::LessOrEqual,
+    BinaryOperator::Greater, BinaryOperator::GreaterOrEqual];
+const KEYS: &'static [&'static str] = &["x", "y", "vertices", "two words", "it's", "", "9lives", "1a", "h i", "inf", "if", "else"];
+const CHARS: &'static [&'static str] = &["a", "Z", "0", " ", "'", "\\", "\"", "\n", "\t", "{", "ö", "@"];
+
+fn gen_string(rng: &mut Rng) -> String {
+    let len = rng.below(8);
+    (0..len).map(|_| rng.pick(CHARS)).collect::<Vec<String>>().concat()
+}
+
+fn gen_entity_path(rng: &mut Rng) -> EntityPath {
+    let root = match rng.below(3) {
+        0 => EntityPath::This,
+        1 => EntityPath::Parent,
+        _ => EntityPath::Named(rng.pick(NAMES))
+    };
+    if rng.below(3) == 0 {
+        EntityPath::Search(Box::new(root), rng.pick(NAMES))
+    } else {
+        root
+    }
+}
+
+fn gen_named_prop_ref(rng: &mut Rng) -> NamedPropRef {
+    NamedPropRef::new(gen_entity_path(rng), &rng.pick(NAMES))
+}
+
+fn gen_floats(rng: &mut Rng, len: usize) -> Vec<f32> {
+    (0..len).map(|_| rng.float()).collect()
+}
+
+fn gen_pon(rng: &mut Rng, depth: u32) -> Pon {
+    let n_variants = if depth > 0 { 21 } else { 12 };
+    match rng.below(n_variants) {
+        0 => Pon::Nil,
+        1 => Pon::Boolean(rng.below(2) == 0),
+        2 => Pon::Integer(rng.next() as i64),
+        3 => Pon::Float(rng.float()),
+        4 => Pon::String(gen_string(rng)),
+        5 => {
+            let len = rng.below(5) as usize;
+            Pon::FloatArray(gen_floats(rng, len))
+        },
+        6 => Pon::IntegerArray((0..rng.below(5)).map(|_| rng.next() as i64).collect()),
+        7 => {
+            let v = gen_floats(rng, 3);
+            Pon::Vector3(Vector3::new(v[0], v[1], v[2]))
+        },
+        8 => {
+            let v = gen_floats(rng, 4);
+            Pon::Vector4(Vector4::new(v[0], v[1], v[2], v[3]))
+        },
+        9 => {
+            let v = gen_floats(rng, 16);
+            Pon::Matrix4(Matrix4::new(
+                v[0], v[1], v[2], v[3],
+                v[4], v[5], v[6], v[7],
+                v[8], v[9], v[10], v[11],
+                v[12], v[13], v[14], v[15]))
+        },
+        10 => Pon::Reference(gen_named_prop_ref(rng)),
+        11 => Pon::DependencyReference(gen_named_prop_ref(rng), None),
+        12 => Pon::Array((0..rng.below(4)).map(|_| gen_pon(rng, depth - 1)).collect()),
+        13 => {
+            let mut hm = PonObject::new();
+            for _ in 0..rng.below(4) {
+                let key = rng.pick(KEYS);
+                hm.insert(key, gen_pon(rng, depth - 1));
+            }
+            Pon::Object(hm)
+        },
+        14 => {
+            let type_name = rng.pick(NAMES);
+            Pon::new_typed_pon(&type_name, gen_pon(rng, depth - 1))
+        },
+        15 => Pon::Expression(Box::new(PonExpression::Unary(UnaryOperator::Neg, gen_pon(rng, depth - 1)))),
+        16 => Pon::Expression(Box::new(PonExpression::Conditional(gen_pon(rng, depth - 1), gen_pon(rng, depth - 1), gen_pon(rng, depth - 1)))),
+        17 => Pon::Expression(Box::new(PonExpression::Fallback(gen_pon(rng, depth - 1), gen_pon(rng, depth - 1)))),
+        18 => {
+            // Text and values alternate, two text parts in a row would be read back as one
+            let mut parts = vec![];
+            for i in 0..rng.below(4) {
+                let text = gen_string(rng) + "`${";
+                if i % 2 == 0 {
+                    parts.push(TemplatePart::Text(text));
+                } else {
+                    parts.push(TemplatePart::Value(gen_pon(rng, depth - 1)));
+                }
+            }
+            Pon::Expression(Box::new(PonExpression::Template(parts)))
+        },
+        19 => {
+            // a keyword followed by ( isn't a call
+            let names: Vec<&'static str> = NAMES.iter().cloned().filter(|name| !is_keyword(name)).collect();
+            let name = rng.pick(&names);
+            // without arguments it would be written as name(), which is a typed value
+            let args = (0..rng.below(3) + 1).map(|_| gen_pon(rng, depth - 1)).collect();
+            Pon::Expression(Box::new(PonExpression::Call(name, args)))
+        },
+        _ => {
+            let op = OPERATORS[rng.below(OPERATORS.len() as u64) as usize];
+            Pon::Expression(Box::new(PonExpression::Binary(op, gen_pon(rng, depth - 1), gen_pon(rng, depth - 1))))
+        }
+    }
+}
+
+fn assert_round_trip(pon: &Pon) {
+    for options in vec![PonStringifyOptions::default(), PonStringifyOptions::compact()] {
+        let string = pon.to_string_with(&options);
+        let parsed = Pon::from_string(&string);
+        if parsed != Ok(pon.clone()) {
+            panic!("{:?} was written as {} but parsed back as {:?}", pon, string, parsed);
+        }
+    }
+}
+
+#[test]
+fn test_round_trip_generated() {
+    let mut rng = Rng(0x2545F4914F6CDD1D);
+    for _ in 0..2000 {
+        let pon = gen_pon(&mut rng, 3);
+        assert_round_trip(&pon);
+    }
+}
+
+#[test]
+fn test_round_trip_floats() {
+    for v in vec![0.1, 1.0 / 3.0, 1e-30, 3.4028235e38, -0.0, 16777217.0, 1.17549435e-38, ::std::f32::INFINITY, ::std::f32::NEG_INFINITY] {
+        assert_round_trip(&Pon::Float(v));
+    }
+}
+
+#[test]
+fn test_round_trip_string_quotes() {
+    assert_round_trip(&Pon::String("it's a \\ test".to_string()));
+    assert_eq!(Pon::from_string(r"'it\'s'"), Ok(Pon::String("it's".to_string())));
+}
+
+#[test]
+fn test_round_trip_typed_arrays() {
+    assert_eq!(Pon::FloatArray(vec![1.0, -0.5]).to_string(), "#f32[1.0, -0.5]");
+    assert_eq!(Pon::IntegerArray(vec![1, -5]).to_string(), "#i64[1, -5]");
+    assert_round_trip(&Pon::FloatArray(vec![]));
+    assert_round_trip(&Pon::Vector3(Vector3::new(1.0, 2.0, 3.0)));
+}
+
+#[test]
+fn test_round_trip_object_keys() {
+    let pon = Pon::Object(hashmap!{
+        "plain" => Pon::Integer(1),
+        "with space" => Pon::Integer(2)
+    });
+    assert_round_trip(&pon);
+}
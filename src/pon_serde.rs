@@ -0,0 +1,417 @@
+use pon::*;
+use document::*;
+use serde::ser::{Serialize, Serializer, SerializeStruct};
+use serde::de::{self, Deserialize, Deserializer, DeserializeOwned, DeserializeSeed, Visitor,
+    SeqAccess, MapAccess, EnumAccess, VariantAccess, IntoDeserializer};
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+use std::vec;
+
+// Pon and the types around it are serialized as their pon text, so nothing is lost on the way
+// through formats that don't know about references, expressions or typed pons.
+
+impl Serialize for Pon {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl Serialize for TypedPon {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Pon::TypedPon(Box::new(self.clone())).serialize(serializer)
+    }
+}
+
+impl Serialize for NamedPropRef {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl Serialize for EntityPath {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+struct PonTextVisitor;
+
+impl<'de> Visitor<'de> for PonTextVisitor {
+    type Value = String;
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a string of pon")
+    }
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<String, E> {
+        Ok(v.to_string())
+    }
+}
+
+fn parse_error<E: de::Error>(err: PonParseError) -> E {
+    E::custom(err.to_string())
+}
+
+impl<'de> Deserialize<'de> for Pon {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Pon, D::Error> {
+        let text = try!(deserializer.deserialize_str(PonTextVisitor));
+        Pon::from_string(&text).map_err(parse_error)
+    }
+}
+
+impl<'de> Deserialize<'de> for TypedPon {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<TypedPon, D::Error> {
+        match try!(Pon::deserialize(deserializer)) {
+            Pon::TypedPon(box typed_pon) => Ok(typed_pon),
+            other => Err(de::Error::custom(format!("expected a typed pon, found {}", other.to_string())))
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for NamedPropRef {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<NamedPropRef, D::Error> {
+        let text = try!(deserializer.deserialize_str(PonTextVisitor));
+        NamedPropRef::from_string(&text).map_err(parse_error)
+    }
+}
+
+impl<'de> Deserialize<'de> for EntityPath {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<EntityPath, D::Error> {
+        let text = try!(deserializer.deserialize_str(PonTextVisitor));
+        EntityPath::from_string(&text).map_err(parse_error)
+    }
+}
+
+// Properties are written as a map from key to pon text, in the snapshot's (sorted) order
+struct SnapshotProperties<'a>(&'a Vec<(String, Pon)>);
+
+impl<'a> Serialize for SnapshotProperties<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.0.iter().map(|&(ref key, ref value)| (key, value)))
+    }
+}
+
+impl Serialize for EntitySnapshot {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = try!(serializer.serialize_struct("EntitySnapshot", 4));
+        try!(state.serialize_field("type_name", &self.type_name));
+        try!(state.serialize_field("name", &self.name));
+        try!(state.serialize_field("properties", &SnapshotProperties(&self.properties)));
+        try!(state.serialize_field("children", &self.children));
+        state.end()
+    }
+}
+
+impl Serialize for DocumentSnapshot {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = try!(serializer.serialize_struct("DocumentSnapshot", 1));
+        try!(state.serialize_field("root", &self.root));
+        state.end()
+    }
+}
+
+const ENTITY_SNAPSHOT_FIELDS: &'static [&'static str] = &["type_name", "name", "properties", "children"];
+
+struct EntitySnapshotVisitor;
+
+impl<'de> Visitor<'de> for EntitySnapshotVisitor {
+    type Value = EntitySnapshot;
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an entity")
+    }
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<EntitySnapshot, A::Error> {
+        let mut type_name = None;
+        let mut name = None;
+        let mut properties: Option<BTreeMap<String, Pon>> = None;
+        let mut children = None;
+        while let Some(key) = try!(map.next_key::<String>()) {
+            match &key[..] {
+                "type_name" => type_name = Some(try!(map.next_value())),
+                "name" => name = Some(try!(map.next_value())),
+                "properties" => properties = Some(try!(map.next_value())),
+                "children" => children = Some(try!(map.next_value())),
+                _ => return Err(de::Error::unknown_field(&key, ENTITY_SNAPSHOT_FIELDS))
+            }
+        }
+        Ok(EntitySnapshot {
+            type_name: try!(type_name.ok_or_else(|| de::Error::missing_field("type_name"))),
+            name: name.unwrap_or(None),
+            properties: properties.unwrap_or_else(BTreeMap::new).into_iter().collect(),
+            children: children.unwrap_or_else(Vec::new)
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for EntitySnapshot {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<EntitySnapshot, D::Error> {
+        deserializer.deserialize_struct("EntitySnapshot", ENTITY_SNAPSHOT_FIELDS, EntitySnapshotVisitor)
+    }
+}
+
+const DOCUMENT_SNAPSHOT_FIELDS: &'static [&'static str] = &["root"];
+
+struct DocumentSnapshotVisitor;
+
+impl<'de> Visitor<'de> for DocumentSnapshotVisitor {
+    type Value = DocumentSnapshot;
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a document")
+    }
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<DocumentSnapshot, A::Error> {
+        let mut root = None;
+        while let Some(key) = try!(map.next_key::<String>()) {
+            match &key[..] {
+                "root" => root = try!(map.next_value()),
+                _ => return Err(de::Error::unknown_field(&key, DOCUMENT_SNAPSHOT_FIELDS))
+            }
+        }
+        Ok(DocumentSnapshot { root: root })
+    }
+}
+
+impl<'de> Deserialize<'de> for DocumentSnapshot {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<DocumentSnapshot, D::Error> {
+        deserializer.deserialize_struct("DocumentSnapshot", DOCUMENT_SNAPSHOT_FIELDS, DocumentSnapshotVisitor)
+    }
+}
+
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct PonDeserializeError(pub String);
+
+impl fmt::Display for PonDeserializeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl error::Error for PonDeserializeError {
+    fn description(&self) -> &str {
+        &self.0
+    }
+}
+
+impl de::Error for PonDeserializeError {
+    fn custom<T: fmt::Display>(msg: T) -> PonDeserializeError {
+        PonDeserializeError(msg.to_string())
+    }
+}
+
+impl From<PonDeserializeError> for PonTranslateErr {
+    fn from(err: PonDeserializeError) -> PonTranslateErr {
+        PonTranslateErr::Generic(err.0)
+    }
+}
+
+// Reads any Deserialize type straight from a pon value. References, expressions and functions
+// are evaluated first. Objects map to structs and maps, arrays and vectors to sequences, and typed
+// pons to enum variants, so `circle { radius: 1.0 }` reads as `Shape::Circle { radius: 1.0 }`.
+pub fn from_pon<T: DeserializeOwned>(pon: &Pon) -> Result<T, PonDeserializeError> {
+    let pon = try!(pon.concretize().map_err(|err| PonDeserializeError(err.to_string())));
+    T::deserialize(PonDeserializer::new(pon))
+}
+
+pub struct PonDeserializer {
+    pon: Pon
+}
+
+impl PonDeserializer {
+    // The pon should be concretized, references and expressions are not evaluated here
+    pub fn new(pon: Pon) -> PonDeserializer {
+        PonDeserializer { pon: pon }
+    }
+}
+
+fn floats(values: Vec<f32>) -> Vec<Pon> {
+    values.into_iter().map(Pon::Float).collect()
+}
+
+impl<'de> Deserializer<'de> for PonDeserializer {
+    type Error = PonDeserializeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, PonDeserializeError> {
+        match self.pon {
+            Pon::Nil => visitor.visit_unit(),
+            Pon::Boolean(v) => visitor.visit_bool(v),
+            Pon::Integer(v) => visitor.visit_i64(v),
+            Pon::Float(v) => visitor.visit_f32(v),
+            Pon::String(v) => visitor.visit_string(v),
+            Pon::Reference(v) => visitor.visit_string(v.to_string()),
+            Pon::Array(v) => visitor.visit_seq(PonSeqAccess { iter: v.into_iter() }),
+            Pon::FloatArray(v) => visitor.visit_seq(PonSeqAccess { iter: floats(v).into_iter() }),
+            Pon::IntegerArray(v) => visitor.visit_seq(PonSeqAccess { iter: v.into_iter().map(Pon::Integer).collect::<Vec<_>>().into_iter() }),
+            Pon::Vector3(v) => visitor.visit_seq(PonSeqAccess { iter: floats(vec![v.x, v.y, v.z]).into_iter() }),
+            Pon::Vector4(v) => visitor.visit_seq(PonSeqAccess { iter: floats(vec![v.x, v.y, v.z, v.w]).into_iter() }),
+            Pon::Matrix4(v) => visitor.visit_seq(PonSeqAccess { iter: floats(vec![
+                    v.x.x, v.x.y, v.x.z, v.x.w,
+                    v.y.x, v.y.y, v.y.z, v.y.w,
+                    v.z.x, v.z.y, v.z.z, v.z.w,
+                    v.w.x, v.w.y, v.w.z, v.w.w
+                ]).into_iter() }),
+            Pon::Object(v) => visitor.visit_map(PonMapAccess { iter: v.into_iter(), value: None }),
+            // a map with the type name as the only key, the way serde writes enum variants
+            Pon::TypedPon(box TypedPon { type_name, data }) =>
+                visitor.visit_map(PonMapAccess { iter: vec![(type_name, data)].into_iter(), value: None }),
+            pon => Err(PonDeserializeError(format!("can't deserialize unevaluated {}", pon.to_string())))
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, PonDeserializeError> {
+        match self.pon {
+            Pon::Nil => visitor.visit_none(),
+            _ => visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, PonDeserializeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(self, _name: &'static str, _variants: &'static [&'static str], visitor: V) -> Result<V::Value, PonDeserializeError> {
+        match self.pon {
+            Pon::String(variant) => visitor.visit_enum(variant.into_deserializer()),
+            Pon::TypedPon(box TypedPon { type_name, data }) => visitor.visit_enum(PonEnumAccess { variant: type_name, data: data }),
+            Pon::Object(object) => {
+                let mut entries: Vec<(String, Pon)> = object.into_iter().collect();
+                if entries.len() != 1 {
+                    return Err(PonDeserializeError("expected an object with a single key for an enum".to_string()));
+                }
+                let (variant, data) = entries.pop().unwrap();
+                visitor.visit_enum(PonEnumAccess { variant: variant, data: data })
+            },
+            pon => Err(PonDeserializeError(format!("expected a string, typed pon or object for an enum, found {}", pon.to_string())))
+        }
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+struct PonSeqAccess {
+    iter: vec::IntoIter<Pon>
+}
+
+impl<'de> SeqAccess<'de> for PonSeqAccess {
+    type Error = PonDeserializeError;
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, PonDeserializeError> {
+        match self.iter.next() {
+            Some(pon) => seed.deserialize(PonDeserializer::new(pon)).map(Some),
+            None => Ok(None)
+        }
+    }
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.iter.len())
+    }
+}
+
+struct PonMapAccess {
+    iter: vec::IntoIter<(String, Pon)>,
+    value: Option<Pon>
+}
+
+impl<'de> MapAccess<'de> for PonMapAccess {
+    type Error = PonDeserializeError;
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, PonDeserializeError> {
+        match self.iter.next() {
+            Some((key, value)) => {
+                self.value = Some(value);
+                seed.deserialize(key.into_deserializer()).map(Some)
+            },
+            None => Ok(None)
+        }
+    }
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, PonDeserializeError> {
+        match self.value.take() {
+            Some(value) => seed.deserialize(PonDeserializer::new(value)),
+            None => Err(PonDeserializeError("value asked for before key".to_string()))
+        }
+    }
+}
+
+struct PonEnumAccess {
+    variant: String,
+    data: Pon
+}
+
+impl<'de> EnumAccess<'de> for PonEnumAccess {
+    type Error = PonDeserializeError;
+    type Variant = PonDeserializer;
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, PonDeserializer), PonDeserializeError> {
+        let variant = try!(seed.deserialize(self.variant.into_deserializer()));
+        Ok((variant, PonDeserializer::new(self.data)))
+    }
+}
+
+impl<'de> VariantAccess<'de> for PonDeserializer {
+    type Error = PonDeserializeError;
+    fn unit_variant(self) -> Result<(), PonDeserializeError> {
+        Ok(())
+    }
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, PonDeserializeError> {
+        seed.deserialize(self)
+    }
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, PonDeserializeError> {
+        self.deserialize_any(visitor)
+    }
+    fn struct_variant<V: Visitor<'de>>(self, _fields: &'static [&'static str], visitor: V) -> Result<V::Value, PonDeserializeError> {
+        self.deserialize_any(visitor)
+    }
+}
+
+
+#[test]
+fn test_from_pon_map() {
+    let pon = Pon::from_string("{ size: 12, scale: 0.5 }").unwrap();
+    let map: BTreeMap<String, f32> = from_pon(&pon).unwrap();
+    assert_eq!(map.get("size"), Some(&12.0));
+    assert_eq!(map.get("scale"), Some(&0.5));
+}
+
+#[test]
+fn test_from_pon_values() {
+    assert_eq!(from_pon::<String>(&Pon::from_string("`hp ${2 * 5}`").unwrap()).unwrap(), "hp 10".to_string());
+    assert_eq!(from_pon::<Vec<f32>>(&Pon::from_string("#vec3[1.0, 0.5, 0.0]").unwrap()).unwrap(), vec![1.0, 0.5, 0.0]);
+}
+
+#[test]
+fn test_from_pon_enum_and_option() {
+    assert_eq!(from_pon::<Option<Vec<f32>>>(&Pon::Nil).unwrap(), None);
+    assert_eq!(from_pon::<Result<f32, String>>(&Pon::from_string("Ok 2").unwrap()).unwrap(), Ok(2.0));
+    assert_eq!(from_pon::<Result<f32, String>>(&Pon::from_string("{ Err: 'bad' }").unwrap()).unwrap(), Err("bad".to_string()));
+}
+
+#[test]
+fn test_from_pon_error() {
+    let pon = Pon::from_string("{ text: 'a' }").unwrap();
+    assert!(from_pon::<BTreeMap<String, f32>>(&pon).is_err());
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn test_serialize_pon() {
+    let pon = Pon::from_string("{ a: @this.x + 1, b: #vec3[1.0, 2.0, 3.0] }").unwrap();
+    let json = ::serde_json::to_string(&pon).unwrap();
+    assert_eq!(json, "\"{ a: @this.x + 1, b: #vec3[1.0, 2.0, 3.0] }\"");
+    assert_eq!(::serde_json::from_str::<Pon>(&json).unwrap(), pon);
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn test_serialize_references() {
+    let named = NamedPropRef::new(EntityPath::Search(Box::new(EntityPath::Parent), "cam".to_string()), "fov");
+    let json = ::serde_json::to_string(&named).unwrap();
+    assert_eq!(json, "\"parent:cam.fov\"");
+    assert_eq!(::serde_json::from_str::<NamedPropRef>(&json).unwrap(), named);
+    assert_eq!(::serde_json::from_str::<EntityPath>("\"this\"").unwrap(), EntityPath::This);
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn test_serialize_snapshot() {
+    let doc = Document::from_string(r#"<Entity name="root" x="5.0"><Sprite y="@root.x" /></Entity>"#).unwrap();
+    let snapshot = doc.snapshot();
+    let json = ::serde_json::to_string(&snapshot).unwrap();
+    assert_eq!(json, r#"{"root":{"type_name":"Entity","name":"root","properties":{"x":"5.0"},"children":[{"type_name":"Sprite","name":null,"properties":{"y":"@root.x"},"children":[]}]}}"#);
+    assert_eq!(::serde_json::from_str::<DocumentSnapshot>(&json).unwrap(), snapshot);
+}
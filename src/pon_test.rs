@@ -1,5 +1,4 @@
 use pon::*;
-use std::collections::HashMap;
 
 #[test]
 fn test_float() {
@@ -13,6 +12,65 @@ fn test_neg_float() {
     assert_eq!(v, Ok(Pon::Float(-5.0)));
 }
 
+#[test]
+fn test_float_forms() {
+    assert_eq!(Pon::from_string("1e-5"), Ok(Pon::Float(1e-5)));
+    assert_eq!(Pon::from_string("2.5E+3"), Ok(Pon::Float(2500.0)));
+    assert_eq!(Pon::from_string(".5"), Ok(Pon::Float(0.5)));
+    assert_eq!(Pon::from_string("5."), Ok(Pon::Float(5.0)));
+    assert_eq!(Pon::from_string("+1.5"), Ok(Pon::Float(1.5)));
+    assert_eq!(Pon::from_string("1_000.000_5"), Ok(Pon::Float(1000.0005)));
+    assert_eq!(Pon::from_string("[1., .5]"), Ok(Pon::Array(vec![Pon::Float(1.0), Pon::Float(0.5)])));
+}
+
+#[test]
+fn test_float_non_finite() {
+    assert_eq!(Pon::from_string("inf"), Ok(Pon::Float(::std::f32::INFINITY)));
+    assert_eq!(Pon::from_string("-inf"), Ok(Pon::Float(::std::f32::NEG_INFINITY)));
+    match Pon::from_string("nan") {
+        Ok(Pon::Float(v)) => assert!(v.is_nan()),
+        v => panic!("Expected nan, got {:?}", v)
+    }
+    assert_eq!(Pon::Float(::std::f32::NEG_INFINITY).to_string(), "-inf");
+    assert_eq!(Pon::from_string("infinity.x"), Ok(Pon::Reference(NamedPropRef::new(EntityPath::Named("infinity".to_string()), "x"))));
+}
+
+#[test]
+fn test_inf_nan_as_names() {
+    assert_eq!(Pon::from_string("inf.x"), Ok(Pon::Reference(NamedPropRef::new(EntityPath::Named("inf".to_string()), "x"))));
+    assert_eq!(Pon::from_string("nan.x"), Ok(Pon::Reference(NamedPropRef::new(EntityPath::Named("nan".to_string()), "x"))));
+    assert_eq!(Pon::from_string("@inf:child.x"), Ok(Pon::DependencyReference(NamedPropRef::new(
+        EntityPath::Search(Box::new(EntityPath::Named("inf".to_string())), "child".to_string()), "x"), None)));
+    assert_eq!(Pon::from_string("nan { }"), Ok(Pon::new_typed_pon("nan", Pon::Object(PonObject::new()))));
+    assert_eq!(Pon::from_string("inf -1"), Ok(Pon::new_typed_pon("inf", Pon::Integer(-1))));
+    assert_eq!(Pon::from_string("inf -inf"), Ok(Pon::new_typed_pon("inf", Pon::Float(::std::f32::NEG_INFINITY))));
+    assert_eq!(Pon::from_string("inf - 1").unwrap().to_string(), "inf - 1");
+    assert_eq!(Pon::from_string("if true then inf else 1.0").unwrap().concretize(), Ok(Pon::Float(::std::f32::INFINITY)));
+    assert_eq!(Pon::from_string("true ? inf : 1.0").unwrap().concretize(), Ok(Pon::Float(::std::f32::INFINITY)));
+    assert_eq!(Pon::from_string("#f32[inf, -inf]"), Ok(Pon::FloatArray(vec![::std::f32::INFINITY, ::std::f32::NEG_INFINITY])));
+}
+
+#[test]
+fn test_float_out_of_range() {
+    assert!(Pon::from_string("1e39").is_err());
+    assert!(Pon::from_string("-1e39").is_err());
+}
+
+#[test]
+fn test_integer_forms() {
+    assert_eq!(Pon::from_string("0xFF"), Ok(Pon::Integer(255)));
+    assert_eq!(Pon::from_string("-0x1_0"), Ok(Pon::Integer(-16)));
+    assert_eq!(Pon::from_string("1_000_000"), Ok(Pon::Integer(1000000)));
+    assert_eq!(Pon::from_string("+7"), Ok(Pon::Integer(7)));
+    assert_eq!(Pon::from_string("-9223372036854775808"), Ok(Pon::Integer(::std::i64::MIN)));
+}
+
+#[test]
+fn test_integer_out_of_range() {
+    assert!(Pon::from_string("9223372036854775808").is_err());
+    assert!(Pon::from_string("0x1_0000_0000_0000_0000").is_err());
+}
+
 #[test]
 fn test_float_empty_space() {
     let v = Pon::from_string(" 5.0 ");
@@ -31,10 +89,41 @@ fn test_string() {
     assert_eq!(v, Ok(Pon::String("hi".to_string())));
 }
 
+#[test]
+fn test_string_escapes() {
+    let v = Pon::from_string(r"'it\'s\n\t\\ \u{e9}\u{1F600}'");
+    assert_eq!(v, Ok(Pon::String("it's\n\t\\ \u{e9}\u{1F600}".to_string())));
+}
+
+#[test]
+fn test_string_unknown_escape_is_literal() {
+    assert_eq!(Pon::from_string(r"'C:\path\docs\user'"), Ok(Pon::String(r"C:\path\docs\user".to_string())));
+    assert_eq!(Pon::from_string(r#""a\qb""#), Ok(Pon::String(r"a\qb".to_string())));
+    assert_eq!(Pon::String(r"C:\path".to_string()).to_string(), r"'C:\\path'");
+}
+
+#[test]
+fn test_string_double_quoted() {
+    let v = Pon::from_string(r#""say \"hi\", it's""#);
+    assert_eq!(v, Ok(Pon::String("say \"hi\", it's".to_string())));
+}
+
+#[test]
+fn test_string_bad_unicode_escape() {
+    assert!(Pon::from_string(r"'\u{110000}'").is_err());
+    assert!(Pon::from_string(r"'\u{d800}'").is_err());
+}
+
+#[test]
+fn test_string_escaped_output() {
+    let v = Pon::String("a'b\n\u{1}".to_string());
+    assert_eq!(v.to_string(), r"'a\'b\n\u{1}'");
+}
+
 #[test]
 fn test_empty_object() {
     let v = Pon::from_string("{}");
-    assert_eq!(v, Ok(Pon::Object(HashMap::new())));
+    assert_eq!(v, Ok(Pon::Object(PonObject::new())));
 }
 
 #[test]
@@ -91,7 +180,7 @@ fn test_transform_nil() {
 #[test]
 fn test_transform_arg() {
     let v = Pon::from_string("static_mesh{ vertices: [0.0, -0.5], indices: [0, 1] }");
-    let mut hm = HashMap::new();
+    let mut hm = PonObject::new();
     hm.insert("vertices".to_string(), Pon::Array(vec![Pon::Float(0.0), Pon::Float(-0.5)]));
     hm.insert("indices".to_string(),  Pon::Array(vec![Pon::Integer(0), Pon::Integer(1)]));
     assert_eq!(v, Ok(Pon::TypedPon(Box::new(TypedPon { type_name: "static_mesh".to_string(), data: Pon::Object(hm) }))));
@@ -115,6 +204,20 @@ fn test_reference() {
     assert_eq!(v, Ok(Pon::Reference(NamedPropRef::new(EntityPath::Named("some".to_string()), "test"))));
 }
 
+#[test]
+fn test_reference_keyword_prefix() {
+    let v = Pon::from_string("@parent_panel.x");
+    assert_eq!(v, Ok(Pon::DependencyReference(NamedPropRef::new(EntityPath::Named("parent_panel".to_string()), "x"), None)));
+    let v = Pon::from_string("this_one.x");
+    assert_eq!(v, Ok(Pon::Reference(NamedPropRef::new(EntityPath::Named("this_one".to_string()), "x"))));
+}
+
+#[test]
+fn test_transform_boolean_prefix() {
+    let v = Pon::from_string("true_color 5");
+    assert_eq!(v, Ok(Pon::new_typed_pon("true_color", Pon::Integer(5))));
+}
+
 #[test]
 fn test_path() {
     let v = Pon::from_string("some:else.test");
@@ -125,5 +228,264 @@ fn test_path() {
 fn test_multiline() {
     let v = Pon::from_string("{
         }");
-    assert_eq!(v, Ok(Pon::Object(HashMap::new())));
+    assert_eq!(v, Ok(Pon::Object(PonObject::new())));
+}
+
+#[test]
+fn test_line_comment() {
+    let v = Pon::from_string("// the mesh
+        static_mesh { // inline
+            vertices: [0.0] // trailing
+        }
+        // done");
+    assert_eq!(v, Ok(Pon::new_typed_pon("static_mesh", Pon::Object(hashmap!{
+        "vertices" => Pon::Array(vec![Pon::Float(0.0)])
+    }))));
+}
+
+#[test]
+fn test_block_comment() {
+    let v = Pon::from_string("[ /* first */ 1, 2 /* second,
+        spanning lines */, /**/3 ]");
+    assert_eq!(v, Ok(Pon::Array(vec![Pon::Integer(1), Pon::Integer(2), Pon::Integer(3)])));
+}
+
+#[test]
+fn test_comment_in_string() {
+    let v = Pon::from_string("'// not /* a comment */'");
+    assert_eq!(v, Ok(Pon::String("// not /* a comment */".to_string())));
+}
+
+#[test]
+fn test_unterminated_block_comment() {
+    assert!(Pon::from_string("5 /* oops").is_err());
+}
+
+#[test]
+fn test_parse_error_position() {
+    let err = Pon::from_string("{\n  a: 5.0,\n  b: ]\n}").err().unwrap();
+    assert_eq!(err.line, 3);
+    assert_eq!(err.column, 6);
+    assert_eq!(err.source_line, "  b: ]".to_string());
+    assert!(err.expected.contains(&"[".to_string()));
+    assert_eq!(err.snippet(), "  b: ]\n     ^".to_string());
+}
+
+#[test]
+fn test_parse_error_snippet_tabs() {
+    let err = Pon::from_string("[\t1,\t?]").err().unwrap();
+    assert_eq!(err.snippet(), "[\t1,\t?]\n \t  \t^".to_string());
+}
+
+#[test]
+fn test_expression_precedence() {
+    let v = Pon::from_string("1 + 2 * 3");
+    assert_eq!(v, Ok(Pon::Expression(Box::new(PonExpression::Binary(BinaryOperator::Add,
+        Pon::Integer(1),
+        Pon::Expression(Box::new(PonExpression::Binary(BinaryOperator::Mul, Pon::Integer(2), Pon::Integer(3)))))))));
+}
+
+#[test]
+fn test_expression_left_associative() {
+    let v = Pon::from_string("5 - 2 - 1");
+    assert_eq!(v, Ok(Pon::Expression(Box::new(PonExpression::Binary(BinaryOperator::Sub,
+        Pon::Expression(Box::new(PonExpression::Binary(BinaryOperator::Sub, Pon::Integer(5), Pon::Integer(2)))),
+        Pon::Integer(1))))));
+}
+
+#[test]
+fn test_expression_comparison() {
+    let v = Pon::from_string("@this.x * 2.0 >= 10");
+    assert_eq!(v, Ok(Pon::Expression(Box::new(PonExpression::Binary(BinaryOperator::GreaterOrEqual,
+        Pon::Expression(Box::new(PonExpression::Binary(BinaryOperator::Mul,
+            Pon::DependencyReference(NamedPropRef::new(EntityPath::This, "x"), None),
+            Pon::Float(2.0)))),
+        Pon::Integer(10))))));
+}
+
+#[test]
+fn test_expression_negation() {
+    let v = Pon::from_string("-@this.x");
+    assert_eq!(v, Ok(Pon::Expression(Box::new(PonExpression::Unary(UnaryOperator::Neg,
+        Pon::DependencyReference(NamedPropRef::new(EntityPath::This, "x"), None))))));
+    assert_eq!(Pon::from_string("-5"), Ok(Pon::Integer(-5)));
+}
+
+#[test]
+fn test_expression_transform_operand() {
+    let v = Pon::from_string("vec3 { x: 1.0 } * 2.0");
+    assert_eq!(v, Ok(Pon::Expression(Box::new(PonExpression::Binary(BinaryOperator::Mul,
+        Pon::new_typed_pon("vec3", Pon::Object(hashmap!{ "x" => Pon::Float(1.0) })),
+        Pon::Float(2.0))))));
+}
+
+#[test]
+fn test_expression_division_and_comments() {
+    let v = Pon::from_string("4 / 2 // half");
+    assert_eq!(v, Ok(Pon::Expression(Box::new(PonExpression::Binary(BinaryOperator::Div, Pon::Integer(4), Pon::Integer(2))))));
+    assert_eq!(Pon::from_string("4 /* not a division */"), Ok(Pon::Integer(4)));
+}
+
+#[test]
+fn test_expression_stringify() {
+    let v = Pon::from_string("(1 + 2) * -(3)").unwrap();
+    assert_eq!(v.to_string(), "(1 + 2) * (-(3))");
+}
+
+#[test]
+fn test_expression_if_then_else() {
+    let v = Pon::from_string("if @this.selected then 'red' else 'grey'");
+    assert_eq!(v, Ok(Pon::Expression(Box::new(PonExpression::Conditional(
+        Pon::DependencyReference(NamedPropRef::new(EntityPath::This, "selected"), None),
+        Pon::String("red".to_string()),
+        Pon::String("grey".to_string()))))));
+}
+
+#[test]
+fn test_expression_ternary() {
+    let v = Pon::from_string("@this.hp > 0 ? 1.0 : 0.5");
+    assert_eq!(v, Ok(Pon::Expression(Box::new(PonExpression::Conditional(
+        Pon::Expression(Box::new(PonExpression::Binary(BinaryOperator::Greater,
+            Pon::DependencyReference(NamedPropRef::new(EntityPath::This, "hp"), None),
+            Pon::Integer(0)))),
+        Pon::Float(1.0),
+        Pon::Float(0.5))))));
+}
+
+#[test]
+fn test_expression_fallback() {
+    let v = Pon::from_string("@this.override ?? 5.0");
+    assert_eq!(v, Ok(Pon::Expression(Box::new(PonExpression::Fallback(
+        Pon::DependencyReference(NamedPropRef::new(EntityPath::This, "override"), None),
+        Pon::Float(5.0))))));
+}
+
+#[test]
+fn test_keyword_prefixed_names() {
+    assert_eq!(Pon::from_string("iffy.x"), Ok(Pon::Reference(NamedPropRef::new(EntityPath::Named("iffy".to_string()), "x"))));
+    assert_eq!(Pon::from_string("elsewhere 5"), Ok(Pon::new_typed_pon("elsewhere", Pon::Integer(5))));
+}
+
+#[test]
+fn test_keywords_as_names() {
+    assert_eq!(Pon::from_string("if { }"), Ok(Pon::new_typed_pon("if", Pon::Object(PonObject::new()))));
+    assert_eq!(Pon::from_string("else.x"), Ok(Pon::Reference(NamedPropRef::new(EntityPath::Named("else".to_string()), "x"))));
+    assert_eq!(Pon::from_string("@then:if.x"), Ok(Pon::DependencyReference(NamedPropRef::new(
+        EntityPath::Search(Box::new(EntityPath::Named("then".to_string())), "if".to_string()), "x"), None)));
+    let v = Pon::from_string("if (if { }) then (then 1) else else.x").unwrap();
+    assert_eq!(v, Pon::Expression(Box::new(PonExpression::Conditional(
+        Pon::new_typed_pon("if", Pon::Object(PonObject::new())),
+        Pon::new_typed_pon("then", Pon::Integer(1)),
+        Pon::Reference(NamedPropRef::new(EntityPath::Named("else".to_string()), "x"))))));
+    assert_eq!(Pon::from_string(&v.to_string()), Ok(v));
+    let v = Pon::new_typed_pon("a", Pon::new_typed_pon("then", Pon::Integer(1)));
+    assert_eq!(Pon::from_string(&v.to_string()), Ok(v));
+}
+
+#[test]
+fn test_call() {
+    let v = Pon::from_string("max(@this.x, 0.0)").unwrap();
+    assert_eq!(v, Pon::Expression(Box::new(PonExpression::Call("max".to_string(), vec![
+        Pon::DependencyReference(NamedPropRef::new(EntityPath::This, "x"), None),
+        Pon::Float(0.0)]))));
+    assert_eq!(v.to_string(), "max(@this.x, 0.0)");
+    assert_eq!(Pon::from_string("max (1)"), Ok(Pon::new_typed_pon("max", Pon::Integer(1))));
+    assert_eq!(Pon::from_string("now()"), Ok(Pon::new_typed_pon("now", Pon::Nil)));
+    assert_eq!(Pon::from_string("now(())"), Ok(Pon::Expression(Box::new(PonExpression::Call("now".to_string(), vec![Pon::Nil])))));
+}
+
+#[test]
+fn test_template() {
+    let v = Pon::from_string("`Score: ${@game.score}\\` $5`");
+    assert_eq!(v, Ok(Pon::Expression(Box::new(PonExpression::Template(vec![
+        TemplatePart::Text("Score: ".to_string()),
+        TemplatePart::Value(Pon::DependencyReference(NamedPropRef::new(EntityPath::Named("game".to_string()), "score"), None)),
+        TemplatePart::Text("` $5".to_string())
+    ])))));
+}
+
+#[test]
+fn test_template_stringify() {
+    let v = Pon::from_string("`a ${1 + 1} $`").unwrap();
+    assert_eq!(v.to_string(), "`a ${1 + 1} \\$`");
+}
+
+#[test]
+fn test_trailing_commas() {
+    assert_eq!(Pon::from_string("[1, 2,]"), Ok(Pon::Array(vec![Pon::Integer(1), Pon::Integer(2)])));
+    assert_eq!(Pon::from_string("{ a: 1, }"), Ok(Pon::Object(hashmap!{ "a" => Pon::Integer(1) })));
+    assert_eq!(Pon::from_string("#f32[1.0,\n]"), Ok(Pon::FloatArray(vec![1.0])));
+    assert!(Pon::from_string("[,]").is_err());
+    assert!(Pon::from_string("[1,,]").is_err());
+}
+
+#[test]
+fn test_stringify_pretty() {
+    let v = Pon::from_string("{ name: 'a long enough name', children: [{ x: 1 }, { x: 2 }], empty: [] }").unwrap();
+    let options = PonStringifyOptions { max_line_width: 30, trailing_commas: true, ..PonStringifyOptions::default() };
+    assert_eq!(v.to_string_with(&options), "{
+  name: 'a long enough name',
+  children: [{ x: 1 }, { x: 2 }],
+  empty: [],
+}");
+    let options = PonStringifyOptions { max_line_width: 20, indent: 4, ..PonStringifyOptions::default() };
+    assert_eq!(v.to_string_with(&options), "{
+    name: 'a long enough name',
+    children: [
+        { x: 1 },
+        { x: 2 }
+    ],
+    empty: []
+}");
+}
+
+#[test]
+fn test_stringify_compact_sorted() {
+    let v = Pon::from_string("{ b: [1, 2], a: 'x' }").unwrap();
+    let options = PonStringifyOptions { sort_keys: true, ..PonStringifyOptions::compact() };
+    assert_eq!(v.to_string_with(&options), "{a:'x',b:[1,2]}");
+    assert_eq!(Pon::from_string(&v.to_string_with(&options)), Ok(v));
+}
+
+#[test]
+fn test_stringify_expression_layout() {
+    let v = Pon::from_string("{ position: #vec3[1.0, 2.0, 3.0] * 2.0 }").unwrap();
+    let options = PonStringifyOptions { max_line_width: 30, ..PonStringifyOptions::default() };
+    assert_eq!(v.to_string_with(&options), "{
+  position: #vec3[
+    1.0,
+    2.0,
+    3.0
+  ] * 2.0
+}");
+    assert_eq!(Pon::from_string(&v.to_string_with(&options)), Ok(v));
+    let v = Pon::from_string("[1 + 2, @this.x ?? 0, max(1, 2), inf - 1]").unwrap();
+    assert_eq!(v.to_string_with(&PonStringifyOptions::compact()), "[1+2,@this.x??0,max(1,2),inf - 1]");
+    assert_eq!(Pon::from_string(&v.to_string_with(&PonStringifyOptions::compact())), Ok(v));
+}
+
+#[test]
+fn test_stringify_line_width() {
+    let options = PonStringifyOptions { max_line_width: 30, ..PonStringifyOptions::default() };
+    let v = Pon::from_string("{ name: ['ööööö', 'ööööö'] }").unwrap();
+    assert_eq!(v.to_string_with(&options), "{ name: ['ööööö', 'ööööö'] }");
+    let options = PonStringifyOptions { max_line_width: 36, ..PonStringifyOptions::default() };
+    let v = Pon::from_string("{ key_with_a_long_name: [100, 200, 300], x: 1 }").unwrap();
+    assert_eq!(v.to_string_with(&options), "{
+  key_with_a_long_name: [
+    100,
+    200,
+    300
+  ],
+  x: 1
+}");
+}
+
+#[test]
+fn test_stringify_float_precision() {
+    let v = Pon::from_string("[0.333333, 2.0, #vec3[1.0, 0.5, 0.25]]").unwrap();
+    let options = PonStringifyOptions { float_precision: FloatPrecision::Decimals(2), ..PonStringifyOptions::default() };
+    assert_eq!(v.to_string_with(&options), "[0.33, 2.00, #vec3[1.00, 0.50, 0.25]]");
+    let options = PonStringifyOptions { float_precision: FloatPrecision::Decimals(0), ..PonStringifyOptions::default() };
+    assert_eq!(Pon::Float(2.4).to_string_with(&options), "2.0");
 }
@@ -13,6 +13,7 @@ pub struct System {
     sub_systems: Vec<Rc<RefCell<Box<ISubSystem>>>>,
     changed_properties: Rc<RefCell<HashSet<PropRef>>>,
     added_entities: Rc<RefCell<Vec<EntityId>>>,
+    removed_entities: Rc<RefCell<Vec<EntityId>>>,
     pub running: bool
 }
 
@@ -23,6 +24,7 @@ impl System {
             sub_systems: vec![],
             changed_properties: Rc::new(RefCell::new(HashSet::new())),
             added_entities: Rc::new(RefCell::new(vec![])),
+            removed_entities: Rc::new(RefCell::new(vec![])),
             running: true
         };
         return pyramid;
@@ -36,6 +38,10 @@ impl System {
         self.document.on_entity_added = Some(Box::new(move |entity_id| {
             added_entities.borrow_mut().push(*entity_id);
         }));
+        let removed_entities = self.removed_entities.clone();
+        self.document.on_entity_removed = Some(Box::new(move |entity_id| {
+            removed_entities.borrow_mut().push(*entity_id);
+        }));
         let changed_properties = self.changed_properties.clone();
         self.document.on_property_set = Some(Box::new(move |entity_id, property_key| {
             changed_properties.borrow_mut().insert(PropRef::new(entity_id, property_key));
@@ -63,25 +69,45 @@ impl System {
                 Err(_) => continue
             };
             for pr in deps {
-                if ips.insert(pr.clone()) {
+                // An if doesn't change when the branch it doesn't take changes
+                let active = match self.document.active_dependencies_of(pr) {
+                    Ok(active) => active.contains(&prop_ref),
+                    Err(_) => true
+                };
+                if active && ips.insert(pr.clone()) {
                     queue.push(pr.clone());
                 }
             }
         }
-        ips.into_iter().collect()
+        let document = &self.document;
+        ips.into_iter().filter(|pr| document.has_entity(&pr.entity_id)).collect()
     }
     pub fn update(&mut self) {
         for system in self.sub_systems.clone() {
             system.borrow_mut().update(self);
         }
+        // Entities that were removed again before their addition was delivered. Subsystems never
+        // heard of them, so their removal isn't delivered either.
+        let mut unannounced = HashSet::new();
         while {
             let ae = mem::replace(&mut *self.added_entities.borrow_mut(), vec![]);
             for e in ae {
-                self.on_entity_added(&e);
+                if self.document.has_entity(&e) {
+                    self.on_entity_added(&e);
+                } else {
+                    unannounced.insert(e);
+                }
+            }
+            let re = mem::replace(&mut *self.removed_entities.borrow_mut(), vec![]);
+            for e in re {
+                if !unannounced.remove(&e) {
+                    self.on_entity_removed(&e);
+                }
             }
             let ips = self.build_property_cascades();
             self.on_property_value_change(&ips);
-            self.changed_properties.borrow().len() > 0 || self.added_entities.borrow().len() > 0
+            self.changed_properties.borrow().len() > 0 || self.added_entities.borrow().len() > 0 ||
+                self.removed_entities.borrow().len() > 0
         } {};
     }
     fn on_entity_added(&mut self, entity_id: &EntityId) {
@@ -89,9 +115,72 @@ impl System {
             system.borrow_mut().on_entity_added(self, entity_id);
         }
     }
+    fn on_entity_removed(&mut self, entity_id: &EntityId) {
+        for system in self.sub_systems.clone() {
+            system.borrow_mut().on_entity_removed(self, entity_id);
+        }
+    }
     fn on_property_value_change(&mut self, prop_refs: &Vec<PropRef>) {
         for system in self.sub_systems.clone() {
             system.borrow_mut().on_property_value_change(self, prop_refs);
         }
     }
 }
+
+
+#[cfg(test)]
+struct EventLog(Rc<RefCell<Vec<String>>>);
+
+#[cfg(test)]
+impl ISubSystem for EventLog {
+    fn on_entity_added(&mut self, _: &mut System, entity_id: &EntityId) {
+        self.0.borrow_mut().push(format!("added {}", entity_id));
+    }
+    fn on_entity_removed(&mut self, _: &mut System, entity_id: &EntityId) {
+        self.0.borrow_mut().push(format!("removed {}", entity_id));
+    }
+    fn on_property_value_change(&mut self, _: &mut System, prop_refs: &Vec<PropRef>) {
+        let mut keys: Vec<String> = prop_refs.iter().map(|pr| format!("changed {}", pr.property_key)).collect();
+        keys.sort();
+        self.0.borrow_mut().extend(keys);
+    }
+}
+
+#[test]
+fn test_added_and_removed_in_same_frame() {
+    let log = Rc::new(RefCell::new(vec![]));
+    let mut system = System::new();
+    system.add_subsystem(Box::new(EventLog(log.clone())));
+    system.set_document(Document::from_string(r#"<Entity />"#).unwrap());
+    log.borrow_mut().clear();
+    let root = system.document().get_root();
+    let kept = system.document_mut().append_entity(root, "Entity", None).unwrap();
+    let temporary = system.document_mut().append_entity(root, "Entity", None).unwrap();
+    system.document_mut().remove_entity(&temporary).unwrap();
+    system.update();
+    assert_eq!(*log.borrow(), vec![format!("added {}", kept)]);
+    system.document_mut().remove_entity(&kept).unwrap();
+    system.update();
+    assert_eq!(*log.borrow(), vec![format!("added {}", kept), format!("removed {}", kept)]);
+}
+
+#[test]
+fn test_conditional_only_cascades_from_taken_branch() {
+    let log = Rc::new(RefCell::new(vec![]));
+    let mut system = System::new();
+    system.add_subsystem(Box::new(EventLog(log.clone())));
+    system.set_document(Document::from_string(r#"<Entity flag="true" a="1" b="2" c="if @this.flag then @this.a else @this.b" />"#).unwrap());
+    let root = system.document().get_root().unwrap();
+    log.borrow_mut().clear();
+    system.document_mut().set_property(&root, "b", Pon::Integer(3)).unwrap();
+    system.update();
+    assert_eq!(*log.borrow(), vec!["changed b".to_string()]);
+    log.borrow_mut().clear();
+    system.document_mut().set_property(&root, "a", Pon::Integer(4)).unwrap();
+    system.update();
+    assert_eq!(*log.borrow(), vec!["changed a".to_string(), "changed c".to_string()]);
+    log.borrow_mut().clear();
+    system.document_mut().set_property(&root, "flag", Pon::Boolean(false)).unwrap();
+    system.update();
+    assert_eq!(*log.borrow(), vec!["changed c".to_string(), "changed flag".to_string()]);
+}